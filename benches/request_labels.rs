@@ -0,0 +1,50 @@
+//! Recording one request's series: formatting the label values and looking
+//! the children up in every vec, as requests did before the series cache, and
+//! the cached lookup `PrometheusFairing` does now. The crate has no library
+//! target, so the modules the fairing needs are compiled in from `src/`, most
+//! of them unused here.
+#![allow(dead_code, unused_imports)]
+
+#[macro_use] extern crate rocket;
+
+#[path = "../src/auth.rs"] mod auth;
+#[path = "../src/config.rs"] mod config;
+#[path = "../src/exemplars.rs"] mod exemplars;
+#[path = "../src/fairing.rs"] mod fairing;
+#[path = "../src/histogram.rs"] mod histogram;
+#[path = "../src/registry.rs"] mod registry;
+#[path = "../src/summary.rs"] mod summary;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rocket::http::Method;
+
+use config::MetricsConfig;
+
+fn request_labels(c: &mut Criterion) {
+    let http = registry::build_registry(&MetricsConfig::default()).unwrap().http;
+    let mut group = c.benchmark_group("request_series");
+    group.bench_function("formatted", |b| b.iter(|| {
+        let (method, code, path) = (black_box(Method::Get), black_box(200u16), black_box("/items/<id>"));
+        let (method, status, path) = (method.as_str().to_string(), code.to_string(), path.to_string());
+        let labels = [method.as_str(), &status, &path];
+        http.requests_total.with_label_values(&labels).inc();
+        http.requests_by_class_total.with_label_values(&[&format!("{}xx", code / 100)]).inc();
+        http.requests_duration.with_label_values(&labels).observe(0.002);
+        http.requests_duration_summary.with_label_values(&[&method, &path]).observe(0.002);
+        http.request_size_bytes_total.with_label_values(&[&method, &path]).inc_by(0.0);
+        http.response_size_bytes_total.with_label_values(&[&method, &path]).inc_by(64.0);
+    }));
+    group.bench_function("cached", |b| b.iter(|| {
+        let series = http.series(black_box(Method::Get), black_box(200), black_box("/items/<id>"));
+        series.total.inc();
+        series.by_class.inc();
+        series.duration.observe(0.002);
+        series.duration_summary.observe(0.002);
+        series.request_size.inc_by(0.0);
+        series.response_size.inc_by(64.0);
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, request_labels);
+criterion_main!(benches);
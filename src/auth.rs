@@ -0,0 +1,59 @@
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+
+/// The token `/metrics` requires, from `METRICS_BEARER_TOKEN`. `None` leaves the
+/// endpoint open.
+pub struct MetricsToken(pub Option<String>);
+
+impl MetricsToken {
+    pub fn from_env() -> Self {
+        MetricsToken(std::env::var("METRICS_BEARER_TOKEN").ok().filter(|token| !token.is_empty()))
+    }
+}
+
+/// Whether `request` carries `Authorization: Bearer <token>` with the
+/// configured token, compared in constant time. Always false when no token is
+/// configured.
+pub fn is_authenticated(request: &Request<'_>) -> bool {
+    let Some(MetricsToken(Some(expected))) = request.rocket().state::<MetricsToken>() else {
+        return false;
+    };
+    request.headers().get_one("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| constant_time_eq(token.as_bytes(), expected.as_bytes()))
+}
+
+/// Compares every byte whatever the first mismatch, so the time taken doesn't
+/// tell how much of a guessed token was right. Only the length can leak.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Request guard for the metrics route: succeeds when no token is configured or
+/// the request carries `Authorization: Bearer <token>`, and fails with 401 otherwise.
+pub struct MetricsAuth;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for MetricsAuth {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.rocket().state::<MetricsToken>() {
+            Some(MetricsToken(Some(_))) if !is_authenticated(request) => Outcome::Error((Status::Unauthorized, ())),
+            _ => Outcome::Success(MetricsAuth),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_whole_tokens() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+}
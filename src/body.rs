@@ -0,0 +1,155 @@
+use std::ops::Deref;
+
+use rocket::data::{self, Data, FromData};
+use rocket::serde::json::{self, Json};
+use rocket::serde::Deserialize;
+use rocket::Request;
+use serde::de::{self, DeserializeSeed, Deserializer, SeqAccess, Visitor};
+use serde_json::error::Category;
+use serde_json::Value;
+
+use crate::registry::AppMetrics;
+
+/// Why the request's JSON body was rejected, for the 400 and 422 catchers to
+/// put in the error message, along with the unknown field that caused it.
+#[derive(Default)]
+pub struct JsonRejection {
+    pub message: Option<String>,
+    pub unknown_field: Option<String>,
+}
+
+/// A JSON body guard like `Json<T>` that also records why a body was rejected,
+/// since catchers only learn the status. Unknown fields, which `Item` and
+/// `ItemPatch` deny, are named in the rejection and counted in
+/// `http_validation_errors_total` rather than as JSON errors.
+pub struct CheckedJson<T>(pub T);
+
+impl<T> CheckedJson<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for CheckedJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T: Deserialize<'r>> FromData<'r> for CheckedJson<T> {
+    type Error = json::Error<'r>;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        match Json::<T>::from_data(request, data).await {
+            data::Outcome::Success(body) => data::Outcome::Success(CheckedJson(body.into_inner())),
+            data::Outcome::Forward(forward) => data::Outcome::Forward(forward),
+            data::Outcome::Error((status, e)) => {
+                let rejection = match &e {
+                    json::Error::Io(e) => JsonRejection { message: Some(format!("could not read body: {}", e)), unknown_field: None },
+                    json::Error::Parse(body, e) => {
+                        let unknown = (e.classify() == Category::Data)
+                            .then(|| unknown_field(body, accepted_fields::<T>()))
+                            .flatten();
+                        if unknown.is_some() {
+                            if let (Some(route), Some(metrics)) = (request.route(), request.rocket().state::<AppMetrics>()) {
+                                metrics.http_validation_errors_total.with_label_values(&[route.uri.path()]).inc();
+                            }
+                        }
+                        JsonRejection { message: Some(format!("invalid JSON body: {}", e)), unknown_field: unknown }
+                    }
+                };
+                request.local_cache(|| rejection);
+                data::Outcome::Error((status, e))
+            }
+        }
+    }
+}
+
+/// The first key of `body` that isn't one of `fields`, or, for an array of
+/// objects, of its first element with one, as `[<index>].<key>`.
+fn unknown_field(body: &str, fields: &[&str]) -> Option<String> {
+    let unknown = |value: &Value| value.as_object()?.keys().find(|key| !fields.contains(&key.as_str())).cloned();
+    match serde_json::from_str::<Value>(body).ok()? {
+        Value::Array(elements) => elements.iter()
+            .enumerate()
+            .find_map(|(index, element)| Some(format!("[{}].{}", index, unknown(element)?))),
+        object => unknown(&object),
+    }
+}
+
+/// The fields `T` accepts when it is a struct or a sequence of structs, as its
+/// derived `Deserialize` names them to the deserializer. Empty for other types.
+fn accepted_fields<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldNames(&mut fields));
+    fields
+}
+
+/// A deserializer that records the field names of the struct it is asked for
+/// instead of producing one, looking into sequences for their elements.
+struct FieldNames<'f>(&'f mut &'static [&'static str]);
+
+impl<'de> Deserializer<'de> for FieldNames<'_> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("not a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = fields;
+        Err(de::Error::custom("fields recorded"))
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_seq(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option unit
+        unit_struct newtype_struct tuple tuple_struct map enum identifier ignored_any
+    }
+}
+
+impl<'de> SeqAccess<'de> for FieldNames<'_> {
+    type Error = de::value::Error;
+
+    fn next_element_seed<S: DeserializeSeed<'de>>(&mut self, seed: S) -> Result<Option<S::Value>, Self::Error> {
+        seed.deserialize(FieldNames(&mut *self.0)).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Sample {
+        name: String,
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn reads_the_fields_a_struct_accepts() {
+        assert_eq!(accepted_fields::<Sample>(), ["name", "tags"]);
+        assert_eq!(accepted_fields::<Vec<Sample>>(), ["name", "tags"]);
+        assert!(accepted_fields::<String>().is_empty());
+    }
+
+    #[test]
+    fn finds_the_unknown_field() {
+        let fields = ["name", "tags"];
+        assert_eq!(unknown_field(r#"{"name": "a", "color": "red"}"#, &fields).as_deref(), Some("color"));
+        assert_eq!(unknown_field(r#"[{"name": "a"}, {"size": 1}]"#, &fields).as_deref(), Some("[1].size"));
+        assert_eq!(unknown_field(r#"{"name": 1}"#, &fields), None);
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use rocket::figment::Figment;
+
+/// Buckets (in seconds) for the request duration histogram, tuned for JSON
+/// endpoints that usually answer in a few milliseconds.
+pub const DEFAULT_DURATION_BUCKETS: [f64; 9] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+pub const DEFAULT_SLOW_REQUEST_THRESHOLD: Duration = Duration::from_millis(500);
+
+pub const DEFAULT_CLIENT_BUCKETS: u64 = 64;
+
+pub const DEFAULT_MAX_PATH_CARDINALITY: usize = 200;
+
+/// Largest JSON request body accepted, in bytes.
+pub const DEFAULT_MAX_BODY_BYTES: u64 = 64 * 1024;
+
+/// Route templates always left out of the request metrics: the scrape and
+/// probe endpoints, which would otherwise flood them.
+pub const DEFAULT_EXCLUDED_PATHS: [&str; 3] = ["/metrics", "/healthz", "/readyz"];
+
+/// Settings for the HTTP instrumentation, read from the environment at launch.
+#[derive(Clone, Debug)]
+pub struct MetricsConfig {
+    pub namespace: String,
+    pub duration_buckets: Vec<f64>,
+    /// Buckets replacing `duration_buckets` for single routes, by `route_bucket_key`.
+    pub route_buckets: HashMap<String, Vec<f64>>,
+    /// Also emit pre-rename metric names (e.g. `http_request_total`) for one release.
+    pub legacy_names: bool,
+    /// Requests taking longer are logged and counted in `http_slow_requests_total`.
+    pub slow_request_threshold: Duration,
+    /// Route templates (e.g. `/items/<id>`) whose requests aren't recorded,
+    /// `DEFAULT_EXCLUDED_PATHS` among them.
+    pub excluded_paths: Vec<String>,
+    /// Add an `X-Response-Time-Ms` header with the measured duration to responses.
+    pub response_time_header: bool,
+    /// Number of `client_bucket` values client IPs are hashed into.
+    pub client_buckets: u64,
+    /// Fraction of requests observed into the duration histogram and summary.
+    pub duration_sample_rate: f64,
+    /// Distinct `path` label values recorded before further ones become `other`.
+    pub max_path_cardinality: usize,
+    /// What is logged for each completed request.
+    pub access_log: AccessLog,
+    /// Sort scrapes explicitly instead of relying on the registry's order.
+    pub stable_metrics_order: bool,
+}
+
+/// How recorded requests are logged once they complete, from `ACCESS_LOG`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessLog {
+    /// A `request completed` tracing event in the request's span (the default).
+    Tracing,
+    /// One JSON object per request and line, on stdout or in `ACCESS_LOG_FILE`.
+    Json,
+    Off,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            namespace: String::new(),
+            duration_buckets: DEFAULT_DURATION_BUCKETS.to_vec(),
+            route_buckets: HashMap::new(),
+            legacy_names: false,
+            slow_request_threshold: DEFAULT_SLOW_REQUEST_THRESHOLD,
+            excluded_paths: DEFAULT_EXCLUDED_PATHS.iter().map(|path| path.to_string()).collect(),
+            response_time_header: false,
+            client_buckets: DEFAULT_CLIENT_BUCKETS,
+            duration_sample_rate: 1.0,
+            max_path_cardinality: DEFAULT_MAX_PATH_CARDINALITY,
+            access_log: AccessLog::Tracing,
+            stable_metrics_order: false,
+        }
+    }
+}
+
+impl MetricsConfig {
+    /// Reads `METRICS_NAMESPACE`, `METRICS_LEGACY_NAMES`, `METRICS_DURATION_BUCKETS`
+    /// (comma-separated seconds), `SLOW_REQUEST_THRESHOLD_MS` and
+    /// `METRICS_EXCLUDE_PATHS` (comma-separated route templates, excluded on
+    /// top of the defaults), `RESPONSE_TIME_HEADER`, `METRICS_CLIENT_BUCKETS`,
+    /// `DURATION_SAMPLE_RATE`, `MAX_PATH_CARDINALITY`, `ACCESS_LOG` and
+    /// `STABLE_METRICS_ORDER`. Invalid values are
+    /// logged and replaced by the defaults.
+    pub fn from_env() -> Self {
+        let mut config = MetricsConfig {
+            namespace: metrics_namespace(),
+            legacy_names: env_flag("METRICS_LEGACY_NAMES"),
+            response_time_header: env_flag("RESPONSE_TIME_HEADER"),
+            stable_metrics_order: env_flag("STABLE_METRICS_ORDER"),
+            ..MetricsConfig::default()
+        };
+        if let Ok(value) = env::var("METRICS_DURATION_BUCKETS") {
+            match parse_buckets(&value) {
+                Some(buckets) => config.duration_buckets = buckets,
+                None => warn!(
+                    "METRICS_DURATION_BUCKETS={:?} is not a strictly increasing list of seconds; using defaults",
+                    value
+                ),
+            }
+        }
+        for (name, value) in env::vars() {
+            let Some(route) = name.strip_prefix("METRICS_BUCKETS_").filter(|route| !route.is_empty()) else {
+                continue;
+            };
+            match parse_buckets(&value) {
+                Some(buckets) => {
+                    config.route_buckets.insert(route.to_ascii_uppercase(), buckets);
+                }
+                None => warn!("{}={:?} is not a strictly increasing list of seconds; using the default buckets", name, value),
+            }
+        }
+        if let Ok(value) = env::var("SLOW_REQUEST_THRESHOLD_MS") {
+            match value.parse::<u64>() {
+                Ok(ms) => config.slow_request_threshold = Duration::from_millis(ms),
+                Err(_) => warn!("SLOW_REQUEST_THRESHOLD_MS={:?} is not a number of milliseconds; using 500", value),
+            }
+        }
+        if let Ok(value) = env::var("METRICS_CLIENT_BUCKETS") {
+            match value.parse::<u64>() {
+                Ok(buckets) if buckets > 0 => config.client_buckets = buckets,
+                _ => warn!("METRICS_CLIENT_BUCKETS={:?} is not a positive number; using 64", value),
+            }
+        }
+        if let Ok(value) = env::var("DURATION_SAMPLE_RATE") {
+            match value.parse::<f64>() {
+                Ok(rate) if (0.0..=1.0).contains(&rate) => config.duration_sample_rate = rate,
+                _ => warn!("DURATION_SAMPLE_RATE={:?} is not between 0.0 and 1.0; using 1.0", value),
+            }
+        }
+        if let Ok(value) = env::var("MAX_PATH_CARDINALITY") {
+            match value.parse::<usize>() {
+                Ok(max) => config.max_path_cardinality = max,
+                Err(_) => warn!("MAX_PATH_CARDINALITY={:?} is not a number; using 200", value),
+            }
+        }
+        if let Ok(value) = env::var("ACCESS_LOG") {
+            match value.to_ascii_lowercase().as_str() {
+                "tracing" => config.access_log = AccessLog::Tracing,
+                "json" => config.access_log = AccessLog::Json,
+                "off" => config.access_log = AccessLog::Off,
+                _ => warn!("ACCESS_LOG={:?} is not one of tracing, json or off; using tracing", value),
+            }
+        }
+        if let Ok(value) = env::var("METRICS_EXCLUDE_PATHS") {
+            for path in value.split(',').map(str::trim).filter(|path| !path.is_empty()) {
+                if !config.excluded_paths.iter().any(|excluded| excluded == path) {
+                    config.excluded_paths.push(path.to_string());
+                }
+            }
+        }
+        config
+    }
+}
+
+/// Merges `BIND_ADDRESS` and `PORT` over `figment`. Unset or invalid values
+/// (logged) leave Rocket's own address and port in place, i.e. `Rocket.toml`,
+/// `ROCKET_ADDRESS`/`ROCKET_PORT` or the defaults.
+pub fn bind_figment(figment: Figment) -> Figment {
+    let mut figment = figment;
+    if let Ok(value) = env::var("BIND_ADDRESS") {
+        match value.parse::<IpAddr>() {
+            Ok(address) => figment = figment.merge(("address", address)),
+            Err(_) => error!("BIND_ADDRESS={:?} is not an IP address; using Rocket's configured address", value),
+        }
+    }
+    if let Ok(value) = env::var("PORT") {
+        match value.parse::<u16>() {
+            Ok(port) => figment = figment.merge(("port", port)),
+            Err(_) => error!("PORT={:?} is not a port number (0-65535); using Rocket's configured port", value),
+        }
+    }
+    figment
+}
+
+/// Sets Rocket's `json` data limit, which every item body is read under, to
+/// `MAX_BODY_BYTES`; larger bodies are rejected with 413. Unset or invalid
+/// values (the latter logged) allow 64 KiB.
+pub fn limits_figment(figment: Figment) -> Figment {
+    let limit = match env::var("MAX_BODY_BYTES") {
+        Ok(value) => value.parse::<u64>().ok().filter(|limit| *limit > 0).unwrap_or_else(|| {
+            warn!("MAX_BODY_BYTES={:?} is not a positive number of bytes; allowing {}", value, DEFAULT_MAX_BODY_BYTES);
+            DEFAULT_MAX_BODY_BYTES
+        }),
+        Err(_) => DEFAULT_MAX_BODY_BYTES,
+    };
+    figment.merge(("limits.json", limit))
+}
+
+/// The port of the separate admin server, from `ADMIN_PORT`. `None` when unset
+/// or invalid (logged), which keeps the admin endpoints on the main port.
+pub fn admin_port() -> Option<u16> {
+    let value = env::var("ADMIN_PORT").ok()?;
+    match value.parse::<u16>() {
+        Ok(port) => Some(port),
+        Err(_) => {
+            error!("ADMIN_PORT={:?} is not a port number (0-65535); serving the admin endpoints on the main port", value);
+            None
+        }
+    }
+}
+
+/// Enables TLS from `TLS_CERT_PATH` and `TLS_KEY_PATH` (PEM files) when both are
+/// set. With neither set `figment` is returned as is and Rocket keeps serving
+/// plain HTTP; setting only one is an error.
+pub fn tls_figment(figment: Figment) -> Result<Figment, String> {
+    let cert = env::var_os("TLS_CERT_PATH").filter(|path| !path.is_empty());
+    let key = env::var_os("TLS_KEY_PATH").filter(|path| !path.is_empty());
+    match (cert, key) {
+        (Some(cert), Some(key)) => Ok(figment
+            .merge(("tls.certs", PathBuf::from(cert)))
+            .merge(("tls.key", PathBuf::from(key)))),
+        (None, None) => Ok(figment),
+        (Some(_), None) => Err("TLS_CERT_PATH is set but TLS_KEY_PATH is not; set both to enable TLS".to_string()),
+        (None, Some(_)) => Err("TLS_KEY_PATH is set but TLS_CERT_PATH is not; set both to enable TLS".to_string()),
+    }
+}
+
+/// True when the variable is set to `1` or `true`.
+pub fn env_flag(name: &str) -> bool {
+    env::var(name).map(|value| value == "1" || value.eq_ignore_ascii_case("true")).unwrap_or(false)
+}
+
+/// Prefix applied to every metric name, from `METRICS_NAMESPACE`. Empty by
+/// default, which leaves names unprefixed.
+pub fn metrics_namespace() -> String {
+    env::var("METRICS_NAMESPACE").unwrap_or_default()
+}
+
+/// The `<route>` of the `METRICS_BUCKETS_<route>` variable for a route
+/// template: its letters and digits uppercased, with runs of anything else as
+/// one `_`, so `/items/<id>` is `ITEMS_ID`. `/` is `ROOT`.
+pub fn route_bucket_key(route: &str) -> String {
+    let key = route.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .map(str::to_ascii_uppercase)
+        .collect::<Vec<_>>()
+        .join("_");
+    if key.is_empty() { "ROOT".to_string() } else { key }
+}
+
+fn parse_buckets(value: &str) -> Option<Vec<f64>> {
+    let buckets = value.split(',')
+        .map(|bucket| bucket.trim().parse::<f64>().ok().filter(|b| b.is_finite()))
+        .collect::<Option<Vec<f64>>>()?;
+    let increasing = buckets.windows(2).all(|pair| pair[0] < pair[1]);
+    (!buckets.is_empty() && increasing).then_some(buckets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route_bucket_keys() {
+        assert_eq!(route_bucket_key("/items/<id>"), "ITEMS_ID");
+        assert_eq!(route_bucket_key("/items/search"), "ITEMS_SEARCH");
+        assert_eq!(route_bucket_key("/"), "ROOT");
+    }
+
+    #[test]
+    fn buckets_must_be_increasing_finite_seconds() {
+        assert_eq!(parse_buckets(" 0.1, 0.5,2"), Some(vec![0.1, 0.5, 2.0]));
+        assert_eq!(parse_buckets("0.5,0.1"), None);
+        assert_eq!(parse_buckets("0.1,0.1"), None);
+        assert_eq!(parse_buckets("0.1,inf"), None);
+        assert_eq!(parse_buckets("fast"), None);
+        assert_eq!(parse_buckets(""), None);
+    }
+}
@@ -0,0 +1,85 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Method, Status};
+use rocket::{Build, Request, Response, Rocket};
+
+const ALLOWED_METHODS: &str = "GET, POST, PUT, PATCH, DELETE, OPTIONS";
+
+/// How long browsers may cache a preflight answer, in seconds.
+const PREFLIGHT_MAX_AGE: &str = "86400";
+
+/// Adds CORS headers for requests whose `Origin` is in the allow-list. An entry
+/// of `*` allows every origin. Requests from other origins get no CORS headers,
+/// so browsers keep blocking them.
+pub struct CorsFairing {
+    origins: Vec<String>,
+}
+
+impl CorsFairing {
+    /// Parses a comma-separated `CORS_ALLOW_ORIGINS` value such as
+    /// `https://grafana.example.com,http://localhost:3000`.
+    pub fn new(origins: &str) -> Self {
+        let origins = origins.split(',')
+            .map(|origin| origin.trim().trim_end_matches('/').to_string())
+            .filter(|origin| !origin.is_empty())
+            .collect();
+        CorsFairing { origins }
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.origins.iter().any(|allowed| allowed == "*" || allowed == origin)
+    }
+
+    /// Attaches the fairing along with the route answering preflight requests.
+    pub fn attach(self, rocket: Rocket<Build>) -> Rocket<Build> {
+        rocket.mount("/", routes![preflight]).attach(self)
+    }
+}
+
+/// Answers every CORS preflight; the fairing adds the headers.
+#[options("/<_..>")]
+fn preflight() -> Status {
+    Status::NoContent
+}
+
+#[rocket::async_trait]
+impl Fairing for CorsFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let Some(origin) = request.headers().get_one("Origin") else {
+            return;
+        };
+        if !self.allows(origin) {
+            return;
+        }
+        response.set_raw_header("Access-Control-Allow-Origin", origin.to_string());
+        response.adjoin_raw_header("Vary", "Origin");
+        if request.method() == Method::Options {
+            response.set_raw_header("Access-Control-Allow-Methods", ALLOWED_METHODS);
+            if let Some(headers) = request.headers().get_one("Access-Control-Request-Headers") {
+                response.set_raw_header("Access-Control-Allow-Headers", headers.to_string());
+            }
+            response.set_raw_header("Access-Control-Max-Age", PREFLIGHT_MAX_AGE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_listed_origins() {
+        let cors = CorsFairing::new("https://grafana.example.com/, http://localhost:3000,");
+        assert!(cors.allows("https://grafana.example.com"));
+        assert!(cors.allows("http://localhost:3000"));
+        assert!(!cors.allows("http://localhost:3001"));
+        assert!(CorsFairing::new("*").allows("https://anywhere.example.com"));
+        assert!(!CorsFairing::new("").allows("https://anywhere.example.com"));
+    }
+}
@@ -0,0 +1,44 @@
+use rocket::http::Status;
+use rocket::response::{self, status, Responder};
+use rocket::serde::json::Json;
+use rocket::Request;
+use serde_json::json;
+
+/// Error response of the JSON API, `{"error": {"code": <status>, "message": ...}}`
+/// served with `status`, with a `"field"` naming the offending request field
+/// when there is one.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: Status,
+    pub message: String,
+    pub field: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(status: Status, message: impl Into<String>) -> Self {
+        ApiError { status, message: message.into(), field: None }
+    }
+
+    pub fn with_field(self, field: impl Into<String>) -> Self {
+        ApiError { field: Some(field.into()), ..self }
+    }
+
+    pub fn item_not_found(id: usize) -> Self {
+        ApiError::new(Status::NotFound, format!("Item with id {} not found", id))
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut body = json!({
+            "error": {
+                "code": self.status.code,
+                "message": self.message
+            }
+        });
+        if let Some(field) = self.field {
+            body["error"]["field"] = json!(field);
+        }
+        status::Custom(self.status, Json(body)).respond_to(request)
+    }
+}
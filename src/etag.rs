@@ -0,0 +1,49 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use rocket::http::Header;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::json::Json;
+
+/// Weak entity tag (`W/"<hex>"`) for a representation derived from `value`.
+pub fn weak_etag(value: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    format!("W/\"{:016x}\"", hasher.finish())
+}
+
+/// The tags of the request's `If-None-Match` header, empty when it has none.
+pub struct IfNoneMatch(Vec<String>);
+
+impl IfNoneMatch {
+    /// Weak comparison as `If-None-Match` requires: `W/` prefixes are ignored
+    /// and `*` matches any tag.
+    pub fn matches(&self, etag: &str) -> bool {
+        let opaque = |tag: &str| tag.trim_start_matches("W/").to_string();
+        self.0.iter().any(|tag| tag == "*" || opaque(tag) == opaque(etag))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfNoneMatch {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let tags = request.headers().get("If-None-Match")
+            .flat_map(|value| value.split(','))
+            .map(|tag| tag.trim().to_string())
+            .filter(|tag| !tag.is_empty())
+            .collect();
+        Outcome::Success(IfNoneMatch(tags))
+    }
+}
+
+/// A JSON representation with its `ETag`, or `304 Not Modified` when the client
+/// already holds it.
+#[derive(Responder)]
+pub enum Tagged {
+    #[response(status = 200)]
+    Fresh(Json<serde_json::Value>, Header<'static>),
+    #[response(status = 304)]
+    NotModified((), Header<'static>),
+}
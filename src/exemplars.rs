@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use prometheus::proto::LabelPair;
+use rocket::Request;
+
+/// OpenMetrics caps the combined length of an exemplar's label names and values.
+const MAX_EXEMPLAR_LABEL_CHARS: usize = 128;
+
+/// An observation linked to the trace that produced it.
+#[derive(Clone, Debug)]
+pub struct Exemplar {
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+    /// Seconds since the Unix epoch.
+    pub timestamp: f64,
+}
+
+/// (family name, series labels sorted by name, bucket upper bound bits)
+type BucketKey = (String, Vec<(String, String)>, u64);
+
+/// Latest exemplar per histogram bucket. The `prometheus` crate has no exemplar
+/// support, so they are kept beside the histograms and merged into the output
+/// by `OpenMetricsEncoder`.
+#[derive(Clone, Debug, Default)]
+pub struct Exemplars(Arc<Mutex<HashMap<BucketKey, Exemplar>>>);
+
+impl Exemplars {
+    /// Records `value` as the exemplar of the first bucket in `buckets` that
+    /// holds it (`+Inf` past the last bound).
+    pub fn record(&self, family: &str, labels: &[(&str, &str)], buckets: &[f64], value: f64, trace_id: &str) {
+        let exemplar_labels = vec![("trace_id".to_string(), trace_id.to_string())];
+        let label_chars: usize = exemplar_labels.iter().map(|(name, value)| name.chars().count() + value.chars().count()).sum();
+        if label_chars > MAX_EXEMPLAR_LABEL_CHARS {
+            return;
+        }
+        let bound = buckets.iter().copied().find(|bound| value <= *bound).unwrap_or(f64::INFINITY);
+        let mut series: Vec<(String, String)> = labels.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        series.sort();
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs_f64()).unwrap_or(0.0);
+        let exemplar = Exemplar { labels: exemplar_labels, value, timestamp };
+        self.0.lock().unwrap().insert((family.to_string(), series, bound.to_bits()), exemplar);
+    }
+
+    /// The exemplar of the `bound` bucket of the series with `labels`, if one was recorded.
+    pub fn get(&self, family: &str, labels: &[LabelPair], bound: f64) -> Option<Exemplar> {
+        let mut series: Vec<(String, String)> = labels.iter()
+            .map(|pair| (pair.get_name().to_string(), pair.get_value().to_string()))
+            .collect();
+        series.sort();
+        self.0.lock().unwrap().get(&(family.to_string(), series, bound.to_bits())).cloned()
+    }
+
+    pub fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+/// Trace id of `request`: the trace-id field of a W3C `traceparent` header, or
+/// else the `X-Request-Id` header unless it holds control characters.
+pub fn trace_id(request: &Request<'_>) -> Option<String> {
+    let headers = request.headers();
+    headers.get_one("traceparent")
+        .and_then(|traceparent| traceparent.split('-').nth(1))
+        .filter(|id| id.len() == 32 && id.chars().all(|c| c.is_ascii_hexdigit()))
+        .or_else(|| headers.get_one("X-Request-Id").filter(|id| !id.is_empty() && !id.chars().any(char::is_control)))
+        .map(str::to_string)
+}
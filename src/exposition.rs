@@ -0,0 +1,315 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use prometheus::{Encoder, HistogramTimer, TextEncoder, TEXT_FORMAT};
+use rocket::http::ContentType;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::stream::ByteStream;
+use rocket::response::{self, Responder, Response};
+use rocket::serde::Serialize;
+
+use crate::exemplars::Exemplars;
+use crate::openmetrics::{OpenMetricsEncoder, EOF, OPENMETRICS_FORMAT};
+
+/// Whether the client listed `gzip` in `Accept-Encoding` with a non-zero q-value.
+pub struct AcceptsGzip(pub bool);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AcceptsGzip {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let gzip = request.headers().get("Accept-Encoding")
+            .flat_map(|value| value.split(','))
+            .any(|coding| {
+                let mut parts = coding.split(';').map(str::trim);
+                let name = parts.next().unwrap_or("");
+                let rejected = parts.any(|param| {
+                    param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+                });
+                (name.eq_ignore_ascii_case("gzip") || name == "*") && !rejected
+            });
+        Outcome::Success(AcceptsGzip(gzip))
+    }
+}
+
+/// Exposition format picked from the `Accept` header: OpenMetrics when the
+/// client asks for `application/openmetrics-text`, the Prometheus text format otherwise.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricsFormat {
+    Text,
+    OpenMetrics,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for MetricsFormat {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let openmetrics = request.headers().get("Accept")
+            .flat_map(|value| value.split(','))
+            .any(|media| media.trim().starts_with("application/openmetrics-text"));
+        Outcome::Success(if openmetrics { MetricsFormat::OpenMetrics } else { MetricsFormat::Text })
+    }
+}
+
+/// Bytes of encoded output collected before they are sent as one chunk.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A scrape, encoded while it is written out and gzip-compressed when the
+/// client accepts it. Families are encoded into chunks of about `CHUNK_SIZE`
+/// bytes that go out as they fill up, so the whole exposition is never held in
+/// memory. An encoding error can't change the status anymore once streaming
+/// has begun; it is logged and the body ends early, which in OpenMetrics shows
+/// as the missing `# EOF`.
+pub struct MetricsBody {
+    families: Vec<MetricFamily>,
+    format: MetricsFormat,
+    exemplars: Exemplars,
+    gzip: bool,
+    timer: Option<HistogramTimer>,
+}
+
+impl MetricsBody {
+    /// Prepares `families` for encoding in `format`, with `exemplars` attached to
+    /// histogram buckets in OpenMetrics (the Prometheus text format has no syntax
+    /// for them).
+    pub fn new(families: Vec<MetricFamily>, format: MetricsFormat, exemplars: &Exemplars, gzip: bool) -> Self {
+        MetricsBody { families, format, exemplars: exemplars.clone(), gzip, timer: None }
+    }
+
+    /// Observes `timer` once the last chunk has been produced, so it covers the
+    /// encoding as well.
+    pub fn timed(self, timer: HistogramTimer) -> Self {
+        MetricsBody { timer: Some(timer), ..self }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self.format {
+            MetricsFormat::Text => TEXT_FORMAT,
+            MetricsFormat::OpenMetrics => OPENMETRICS_FORMAT,
+        }
+    }
+}
+
+/// Collects encoded output until it makes up a chunk, compressing it first when
+/// gzip was negotiated. A gzip stream's chunks are its compressed bytes so far.
+enum ChunkWriter {
+    Plain(Vec<u8>),
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+impl ChunkWriter {
+    fn new(gzip: bool) -> Self {
+        if gzip {
+            ChunkWriter::Gzip(GzEncoder::new(Vec::new(), Compression::default()))
+        } else {
+            ChunkWriter::Plain(Vec::new())
+        }
+    }
+
+    fn full(&self) -> bool {
+        match self {
+            ChunkWriter::Plain(buffer) => buffer.len() >= CHUNK_SIZE,
+            ChunkWriter::Gzip(compressor) => compressor.get_ref().len() >= CHUNK_SIZE,
+        }
+    }
+
+    fn take(&mut self) -> Vec<u8> {
+        match self {
+            ChunkWriter::Plain(buffer) => std::mem::take(buffer),
+            ChunkWriter::Gzip(compressor) => std::mem::take(compressor.get_mut()),
+        }
+    }
+
+    /// The rest of the output, including the gzip trailer.
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            ChunkWriter::Plain(buffer) => Ok(buffer),
+            ChunkWriter::Gzip(compressor) => compressor.finish(),
+        }
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        match self {
+            ChunkWriter::Plain(buffer) => buffer.write(bytes),
+            ChunkWriter::Gzip(compressor) => compressor.write(bytes),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ChunkWriter::Plain(buffer) => buffer.flush(),
+            ChunkWriter::Gzip(compressor) => compressor.flush(),
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'r> for MetricsBody {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'r> {
+        let content_type = ContentType::parse_flexible(self.content_type()).unwrap_or(ContentType::Plain);
+        let gzip = self.gzip;
+        let MetricsBody { families, format, exemplars, timer, .. } = self;
+        let openmetrics = OpenMetricsEncoder::with_exemplars(exemplars);
+        let chunks = ByteStream! {
+            let _timer = timer;
+            let mut writer = ChunkWriter::new(gzip);
+            for family in families {
+                let family = std::slice::from_ref(&family);
+                let encoded = match format {
+                    MetricsFormat::Text => TextEncoder::new().encode(family, &mut writer),
+                    MetricsFormat::OpenMetrics => openmetrics.encode_families(family, &mut writer),
+                };
+                if let Err(e) = encoded {
+                    error!("could not encode metric family {}: {}", family[0].get_name(), e);
+                    return;
+                }
+                if writer.full() {
+                    yield writer.take();
+                }
+            }
+            if format == MetricsFormat::OpenMetrics {
+                if let Err(e) = writer.write_all(EOF.as_bytes()) {
+                    error!("could not encode metrics: {}", e);
+                    return;
+                }
+            }
+            match writer.finish() {
+                Ok(rest) => yield rest,
+                Err(e) => error!("could not encode metrics: {}", e),
+            }
+        };
+        let mut response = Response::build_from(chunks.respond_to(request)?);
+        response.header(content_type).raw_header("Vary", "Accept, Accept-Encoding");
+        if gzip {
+            response.raw_header("Content-Encoding", "gzip");
+        }
+        response.ok()
+    }
+}
+
+/// Orders `families` by name, each family's labels by name and its series by
+/// their label values, as `STABLE_METRICS_ORDER` promises.
+pub fn sort_families(families: &mut [MetricFamily]) {
+    families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+    for family in families.iter_mut() {
+        for metric in family.mut_metric().iter_mut() {
+            metric.mut_label().sort_by(|a, b| a.get_name().cmp(b.get_name()));
+        }
+        family.mut_metric().sort_by(|a, b| {
+            let pairs = |metric: &Metric| metric.get_label().iter()
+                .map(|pair| (pair.get_name().to_string(), pair.get_value().to_string()))
+                .collect::<Vec<_>>();
+            pairs(a).cmp(&pairs(b))
+        });
+    }
+}
+
+/// A metric family in the structure served by `/metrics.json`.
+#[derive(Serialize)]
+pub struct FamilyJson {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub help: String,
+    pub samples: Vec<SampleJson>,
+}
+
+/// One sample line of a family; histograms and summaries expand into their
+/// `_bucket`/quantile, `_sum` and `_count` samples as in the text format.
+#[derive(Serialize)]
+pub struct SampleJson {
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+    pub value: f64,
+}
+
+impl FamilyJson {
+    pub fn from_family(family: &MetricFamily) -> Self {
+        let name = family.get_name();
+        let mut samples = Vec::new();
+        for metric in family.get_metric() {
+            let labels: BTreeMap<String, String> = metric.get_label().iter()
+                .map(|pair| (pair.get_name().to_string(), pair.get_value().to_string()))
+                .collect();
+            let mut push = |suffix: &str, extra: Option<(&str, String)>, value: f64| {
+                let mut labels = labels.clone();
+                if let Some((key, value)) = extra {
+                    labels.insert(key.to_string(), value);
+                }
+                samples.push(SampleJson { name: format!("{}{}", name, suffix), labels, value });
+            };
+            match family.get_field_type() {
+                MetricType::COUNTER => push("", None, metric.get_counter().get_value()),
+                MetricType::GAUGE => push("", None, metric.get_gauge().get_value()),
+                MetricType::UNTYPED => push("", None, metric.get_untyped().get_value()),
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    for bucket in histogram.get_bucket() {
+                        let le = bucket.get_upper_bound().to_string();
+                        push("_bucket", Some(("le", le)), bucket.get_cumulative_count() as f64);
+                    }
+                    push("_bucket", Some(("le", "+Inf".to_string())), histogram.get_sample_count() as f64);
+                    push("_sum", None, histogram.get_sample_sum());
+                    push("_count", None, histogram.get_sample_count() as f64);
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        push("", Some(("quantile", quantile.get_quantile().to_string())), quantile.get_value());
+                    }
+                    push("_sum", None, summary.get_sample_sum());
+                    push("_count", None, summary.get_sample_count() as f64);
+                }
+            }
+        }
+        FamilyJson {
+            name: name.to_string(),
+            kind: type_name(family.get_field_type()),
+            help: family.get_help().to_string(),
+            samples,
+        }
+    }
+}
+
+/// A metric family as listed by `/metrics/catalog`: its metadata and the names
+/// of its labels, without any values.
+#[derive(Serialize)]
+pub struct CatalogEntry {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub help: String,
+    pub labels: Vec<String>,
+}
+
+impl CatalogEntry {
+    /// The label names are those of the family's current series, sorted.
+    pub fn from_family(family: &MetricFamily) -> Self {
+        let labels: BTreeSet<String> = family.get_metric().iter()
+            .flat_map(|metric| metric.get_label().iter().map(|pair| pair.get_name().to_string()))
+            .collect();
+        CatalogEntry {
+            name: family.get_name().to_string(),
+            kind: type_name(family.get_field_type()),
+            help: family.get_help().to_string(),
+            labels: labels.into_iter().collect(),
+        }
+    }
+}
+
+/// The metric type as spelled in `# TYPE` lines of the Prometheus text format.
+pub fn type_name(metric_type: MetricType) -> &'static str {
+    match metric_type {
+        MetricType::COUNTER => "counter",
+        MetricType::GAUGE => "gauge",
+        MetricType::HISTOGRAM => "histogram",
+        MetricType::SUMMARY => "summary",
+        MetricType::UNTYPED => "untyped",
+    }
+}
@@ -0,0 +1,766 @@
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+use prometheus::core::Collector;
+use prometheus::{Counter, CounterVec, Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec, Opts, Registry};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Method;
+use rocket::serde::Serialize;
+use rocket::time::format_description::well_known::Rfc3339;
+use rocket::time::OffsetDateTime;
+use rocket::{Build, Data, Request, Response, Rocket};
+use tracing::field::Empty;
+use tracing::Span;
+
+use crate::auth::is_authenticated;
+use crate::config::{AccessLog, MetricsConfig};
+use crate::exemplars::{self, Exemplars};
+use crate::histogram::RouteHistogramVec;
+use crate::registry::register;
+use crate::summary::{Summary, SummaryVec};
+
+struct RequestStart(Instant);
+
+struct RequestMethod(Method);
+
+/// Holds a request's slot in the in-progress gauges. Released by `on_response`,
+/// or when Rocket drops the request without responding (e.g. the connection
+/// task is cancelled); `released` makes sure only one of the two decrements.
+struct InProgress {
+    total: Gauge,
+    by_path: Gauge,
+    released: AtomicBool,
+}
+
+impl InProgress {
+    fn acquire(total: &Gauge, by_path: Gauge) -> Self {
+        total.inc();
+        by_path.inc();
+        InProgress { total: total.clone(), by_path, released: AtomicBool::new(false) }
+    }
+
+    fn release(&self) {
+        if !self.released.swap(true, Ordering::AcqRel) {
+            self.by_path.dec();
+            self.total.dec();
+        }
+    }
+}
+
+impl Drop for InProgress {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// Declared `Content-Length` of the request body; 0 for bodiless, chunked or
+/// otherwise unknown-length requests.
+struct RequestBodySize(u64);
+
+/// `tracing` span covering the request; `status` and `duration_ms` are
+/// recorded on it in `on_response`.
+struct RequestSpan(Span);
+
+/// Whether the request is recorded, decided on first use so every fairing
+/// agrees even if routing ends up elsewhere than predicted.
+struct Instrumented(bool);
+
+/// Trace id the request arrived with, attached as an exemplar to its duration.
+struct TraceId(Option<String>);
+
+/// Longest `path` label value kept, in characters.
+const MAX_PATH_LABEL_CHARS: usize = 128;
+
+/// Sanitized `path` labels, kept only when `label_value` had to change the
+/// route template.
+struct PathLabel(String);
+struct PredictedPathLabel(String);
+
+/// The labelled children `on_response` updates for one route, method and
+/// status. Looked up once and then reused, so recording a request doesn't
+/// format or hash label values.
+#[derive(Clone)]
+pub struct RequestSeries {
+    pub total: Counter,
+    pub legacy_total: Option<Counter>,
+    pub by_class: Counter,
+    pub duration: Histogram,
+    pub duration_summary: Summary,
+    pub request_size: Counter,
+    pub response_size: Counter,
+    pub slow: Counter,
+}
+
+/// `RequestSeries` by route template, then by method and status code.
+type SeriesCache = HashMap<String, HashMap<(Method, u16), RequestSeries>>;
+
+/// One `http_requests_total` series as reported by `/metrics/snapshot`.
+#[derive(Serialize)]
+pub struct RequestTotal {
+    pub method: String,
+    pub status: String,
+    pub path: String,
+    pub count: u64,
+}
+
+/// Request and non-2xx response counts of one route.
+#[derive(Clone, Copy, Default)]
+pub struct RouteTotals {
+    pub requests: f64,
+    pub errors: f64,
+}
+
+/// Records per-request HTTP metrics (totals, duration, in-progress, body sizes)
+/// from Rocket's request/response hooks, so handlers need no instrumentation.
+#[derive(Clone)]
+pub struct PrometheusFairing {
+    pub requests_total: CounterVec,
+    /// `http_request_total`, the pre-rename name, kept only under `METRICS_LEGACY_NAMES`.
+    pub legacy_requests_total: Option<CounterVec>,
+    pub requests_by_class_total: CounterVec,
+    pub requests_duration: RouteHistogramVec,
+    pub requests_duration_summary: SummaryVec,
+    /// Unlabelled, a vec only so that resets can empty it.
+    pub request_interarrival: HistogramVec,
+    pub requests_in_progress: Gauge,
+    /// Set by the sampler in `main` from successive `requests_total` sums.
+    pub requests_per_second: Gauge,
+    /// Set by the sampler in `main` from successive `requests_by_path` readings.
+    pub route_error_ratio: GaugeVec,
+    pub requests_in_progress_by_path: GaugeVec,
+    pub request_size_bytes_total: CounterVec,
+    pub response_size_bytes_total: CounterVec,
+    pub slow_requests_total: CounterVec,
+    /// Incremented by the handlers `timeout::with_timeout` wraps.
+    pub request_timeouts_total: CounterVec,
+    pub responses_by_content_type_total: CounterVec,
+    pub requests_by_client_total: CounterVec,
+    pub requests_by_auth_total: CounterVec,
+    pub requests_by_version_total: CounterVec,
+    pub client_buckets: u64,
+    pub duration_sample_rate: f64,
+    pub max_path_cardinality: usize,
+    pub cardinality_overflow_total: Counter,
+    pub slow_request_threshold: Duration,
+    pub excluded_paths: Vec<String>,
+    pub response_time_header: bool,
+    pub access_log: AccessLog,
+    /// Where `ACCESS_LOG=json` lines go.
+    pub access_log_writer: AccessLogWriter,
+    pub stable_metrics_order: bool,
+    /// Trace ids of recent `requests_duration` observations, one per bucket.
+    pub exemplars: Exemplars,
+    series: Arc<RwLock<SeriesCache>>,
+    /// Held shared by `on_response` while it records a request and exclusively
+    /// by resets, so a request is counted entirely before or entirely after one.
+    recording: Arc<RwLock<()>>,
+    /// `path` label values handed out so far, at most `max_path_cardinality`.
+    paths: Arc<RwLock<HashSet<String>>>,
+    /// Arrival of the previous instrumented request, for `request_interarrival`.
+    last_arrival: Arc<Mutex<Option<Instant>>>,
+}
+
+impl PrometheusFairing {
+    /// Creates the HTTP collectors as described by `config` and registers them with `registry`.
+    pub fn new(registry: &Registry, config: &MetricsConfig) -> prometheus::Result<Self> {
+        let mut fairing = PrometheusFairing {
+            requests_total: CounterVec::new(
+                Opts::new("http_requests_total", "Total HTTP Requests").namespace(&config.namespace),
+                &["method", "status", "path"]
+            )?,
+            legacy_requests_total: None,
+            requests_by_class_total: CounterVec::new(
+                Opts::new("http_requests_by_class_total", "Total HTTP Requests by status class (2xx, 3xx, 4xx, 5xx)")
+                    .namespace(&config.namespace),
+                &["class"]
+            )?,
+            requests_duration: RouteHistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "HTTP Request Duration")
+                    .namespace(&config.namespace)
+                    .buckets(config.duration_buckets.clone()),
+                &["method", "status", "path"],
+                &config.route_buckets
+            )?,
+            requests_duration_summary: SummaryVec::new(
+                Opts::new(
+                    "http_request_duration_summary_seconds",
+                    "HTTP Request Duration quantiles computed in this process. Unlike http_request_duration_seconds \
+                     buckets, quantiles can't be aggregated across instances or routes"
+                ).namespace(&config.namespace),
+                &["method", "path"]
+            )?,
+            request_interarrival: HistogramVec::new(
+                HistogramOpts::new("http_request_interarrival_seconds", "Time between the arrival of successive HTTP requests")
+                    .namespace(&config.namespace)
+                    .buckets(vec![0.001, 0.01, 0.1, 0.5, 1.0, 5.0, 10.0, 60.0]),
+                &[]
+            )?,
+            requests_per_second: Gauge::with_opts(
+                Opts::new("http_requests_per_second", "HTTP requests per second over the last 10 seconds, computed in-process")
+                    .namespace(&config.namespace)
+            )?,
+            route_error_ratio: GaugeVec::new(
+                Opts::new("http_route_error_ratio", "Fraction of non-2xx responses per route over the last 60 seconds, 0 without traffic")
+                    .namespace(&config.namespace),
+                &["path"]
+            )?,
+            requests_in_progress: Gauge::with_opts(
+                Opts::new("http_requests_in_progress", "Number of HTTP requests in progress").namespace(&config.namespace)
+            )?,
+            requests_in_progress_by_path: GaugeVec::new(
+                Opts::new("http_requests_in_progress_by_path", "Number of HTTP requests in progress per route")
+                    .namespace(&config.namespace),
+                &["method", "path"]
+            )?,
+            request_size_bytes_total: CounterVec::new(
+                Opts::new("http_request_size_bytes_total", "Total bytes of HTTP request bodies received").namespace(&config.namespace),
+                &["method", "path"]
+            )?,
+            response_size_bytes_total: CounterVec::new(
+                Opts::new("http_response_size_bytes_total", "Total bytes of HTTP response bodies served").namespace(&config.namespace),
+                &["method", "path"]
+            )?,
+            slow_requests_total: CounterVec::new(
+                Opts::new("http_slow_requests_total", "HTTP requests that took longer than SLOW_REQUEST_THRESHOLD_MS")
+                    .namespace(&config.namespace),
+                &["path"]
+            )?,
+            request_timeouts_total: CounterVec::new(
+                Opts::new("http_request_timeouts_total", "HTTP requests answered with 503 after exceeding REQUEST_TIMEOUT_MS")
+                    .namespace(&config.namespace),
+                &["path"]
+            )?,
+            responses_by_content_type_total: CounterVec::new(
+                Opts::new("http_responses_by_content_type_total", "Total HTTP responses by media type of the Content-Type header")
+                    .namespace(&config.namespace),
+                &["content_type"]
+            )?,
+            requests_by_auth_total: CounterVec::new(
+                Opts::new("http_requests_by_auth_total", "Total HTTP Requests by whether they carried the METRICS_BEARER_TOKEN")
+                    .namespace(&config.namespace),
+                &["auth"]
+            )?,
+            requests_by_version_total: CounterVec::new(
+                Opts::new("http_requests_by_version_total", "Total HTTP Requests by HTTP protocol version")
+                    .namespace(&config.namespace),
+                &["http_version"]
+            )?,
+            requests_by_client_total: CounterVec::new(
+                Opts::new("http_requests_by_client_total", "Total HTTP Requests by hashed client IP bucket (METRICS_CLIENT_BUCKETS)")
+                    .namespace(&config.namespace),
+                &["client_bucket"]
+            )?,
+            client_buckets: config.client_buckets,
+            duration_sample_rate: config.duration_sample_rate,
+            max_path_cardinality: config.max_path_cardinality,
+            cardinality_overflow_total: Counter::with_opts(
+                Opts::new("metrics_cardinality_overflow_total", "Requests recorded under path=\"other\" because MAX_PATH_CARDINALITY was reached")
+                    .namespace(&config.namespace)
+            )?,
+            slow_request_threshold: config.slow_request_threshold,
+            excluded_paths: config.excluded_paths.clone(),
+            response_time_header: config.response_time_header,
+            access_log: config.access_log,
+            access_log_writer: AccessLogWriter::new(io::stdout()),
+            stable_metrics_order: config.stable_metrics_order,
+            exemplars: Exemplars::default(),
+            series: Arc::default(),
+            recording: Arc::default(),
+            paths: Arc::default(),
+            last_arrival: Arc::default(),
+        };
+        register(registry, &fairing.requests_total)?;
+        register(registry, &fairing.requests_by_class_total)?;
+        register(registry, &fairing.requests_duration)?;
+        register(registry, &fairing.requests_duration_summary)?;
+        register(registry, &fairing.request_interarrival)?;
+        register(registry, &fairing.requests_in_progress)?;
+        register(registry, &fairing.requests_per_second)?;
+        register(registry, &fairing.route_error_ratio)?;
+        register(registry, &fairing.requests_in_progress_by_path)?;
+        register(registry, &fairing.request_size_bytes_total)?;
+        register(registry, &fairing.response_size_bytes_total)?;
+        register(registry, &fairing.slow_requests_total)?;
+        register(registry, &fairing.request_timeouts_total)?;
+        register(registry, &fairing.responses_by_content_type_total)?;
+        register(registry, &fairing.requests_by_client_total)?;
+        register(registry, &fairing.requests_by_auth_total)?;
+        register(registry, &fairing.requests_by_version_total)?;
+        register(registry, &fairing.cardinality_overflow_total)?;
+        if config.legacy_names {
+            let legacy = CounterVec::new(
+                Opts::new("http_request_total", "Total HTTP Requests (deprecated, use http_requests_total)")
+                    .namespace(&config.namespace),
+                &["method", "status", "path"]
+            )?;
+            register(registry, &legacy)?;
+            fairing.legacy_requests_total = Some(legacy);
+        }
+        Ok(fairing)
+    }
+
+    /// Zeroes the request counters and histograms by dropping every labelled
+    /// series; they reappear on the next request. The `path` values handed out
+    /// are forgotten, so `MAX_PATH_CARDINALITY` starts over. In-progress gauges
+    /// are left alone since requests in flight still have to decrement them.
+    pub fn reset(&self) {
+        let _recording = self.recording.write().unwrap();
+        self.reset_totals();
+        self.requests_duration.reset();
+        self.requests_duration_summary.reset();
+        self.request_size_bytes_total.reset();
+        self.response_size_bytes_total.reset();
+        self.slow_requests_total.reset();
+        self.request_timeouts_total.reset();
+        self.responses_by_content_type_total.reset();
+        self.requests_by_client_total.reset();
+        self.requests_by_auth_total.reset();
+        self.requests_by_version_total.reset();
+        self.request_interarrival.reset();
+        *self.last_arrival.lock().unwrap() = None;
+        self.cardinality_overflow_total.reset();
+        self.paths.write().unwrap().clear();
+        self.exemplars.clear();
+    }
+
+    /// The current `http_requests_total` series. With `reset`, they are also
+    /// zeroed (along with the legacy and by-class totals) in the same step, so
+    /// every request is in exactly one snapshot.
+    pub fn snapshot(&self, reset: bool) -> Vec<RequestTotal> {
+        let _recording = self.recording.write().unwrap();
+        let totals = self.requests_total.collect().iter()
+            .flat_map(|family| family.get_metric())
+            .map(|metric| {
+                let label = |name: &str| metric.get_label().iter()
+                    .find(|pair| pair.get_name() == name)
+                    .map(|pair| pair.get_value().to_string())
+                    .unwrap_or_default();
+                RequestTotal {
+                    method: label("method"),
+                    status: label("status"),
+                    path: label("path"),
+                    count: metric.get_counter().get_value() as u64,
+                }
+            })
+            .collect();
+        if reset {
+            self.reset_totals();
+        }
+        totals
+    }
+
+    /// `path` as a label value, or `other` once `max_path_cardinality` distinct
+    /// values are in use, so a normalization bug can't explode the series count.
+    fn path_label<'a>(&self, path: &'a str) -> &'a str {
+        if self.paths.read().unwrap().contains(path) {
+            return path;
+        }
+        let mut paths = self.paths.write().unwrap();
+        if !paths.contains(path) {
+            if paths.len() >= self.max_path_cardinality {
+                return "other";
+            }
+            paths.insert(path.to_string());
+        }
+        path
+    }
+
+    /// The sum of every `http_requests_total` series.
+    pub fn total_requests(&self) -> f64 {
+        self.requests_total.collect().iter()
+            .flat_map(|family| family.get_metric())
+            .map(|metric| metric.get_counter().get_value())
+            .sum()
+    }
+
+    /// Requests and non-2xx responses so far per route template, summed over
+    /// methods and statuses of `http_requests_total`.
+    pub fn requests_by_path(&self) -> HashMap<String, RouteTotals> {
+        let mut by_path: HashMap<String, RouteTotals> = HashMap::new();
+        for metric in self.requests_total.collect().iter().flat_map(|family| family.get_metric()) {
+            let label = |name: &str| metric.get_label().iter()
+                .find(|pair| pair.get_name() == name)
+                .map(|pair| pair.get_value())
+                .unwrap_or_default();
+            let count = metric.get_counter().get_value();
+            let totals = by_path.entry(label("path").to_string()).or_default();
+            totals.requests += count;
+            if !label("status").starts_with('2') {
+                totals.errors += count;
+            }
+        }
+        by_path
+    }
+
+    /// Zeroes the request count vecs. Callers hold `recording` exclusively.
+    fn reset_totals(&self) {
+        // Cached children would no longer be part of their vecs. Holding the
+        // lock keeps `series` from caching one that is about to be dropped.
+        let mut series = self.series.write().unwrap();
+        series.clear();
+        self.requests_total.reset();
+        if let Some(legacy) = &self.legacy_requests_total {
+            legacy.reset();
+        }
+        self.requests_by_class_total.reset();
+    }
+
+    /// The children for requests to `path` answered with `code`, created and
+    /// cached on first use.
+    pub fn series(&self, method: Method, code: u16, path: &str) -> RequestSeries {
+        let cached = self.series.read().unwrap()
+            .get(path)
+            .and_then(|by_status| by_status.get(&(method, code)))
+            .cloned();
+        if let Some(series) = cached {
+            return series;
+        }
+        let mut cache = self.series.write().unwrap();
+        let by_status = cache.entry(path.to_string()).or_default();
+        by_status.entry((method, code))
+            .or_insert_with(|| {
+                let status = code.to_string();
+                let labels = [method.as_str(), &status, path];
+                RequestSeries {
+                    total: self.requests_total.with_label_values(&labels),
+                    legacy_total: self.legacy_requests_total.as_ref().map(|legacy| legacy.with_label_values(&labels)),
+                    by_class: self.requests_by_class_total.with_label_values(&[&format!("{}xx", code / 100)]),
+                    duration: self.requests_duration.with_label_values(&labels),
+                    duration_summary: self.requests_duration_summary.with_label_values(&[method.as_str(), path]),
+                    request_size: self.request_size_bytes_total.with_label_values(&[method.as_str(), path]),
+                    response_size: self.response_size_bytes_total.with_label_values(&[method.as_str(), path]),
+                    slow: self.slow_requests_total.with_label_values(&[path]),
+                }
+            })
+            .clone()
+    }
+
+    /// Observes `duration` in `series` and, when the request carried a trace
+    /// id, keeps it as the exemplar of the bucket the observation fell into.
+    fn observe_with_exemplar(&self, series: &RequestSeries, labels: [&str; 3], duration: f64, trace_id: Option<&str>) {
+        series.duration.observe(duration);
+        if let Some(trace_id) = trace_id {
+            let desc = &self.requests_duration.desc()[0];
+            let series: Vec<(&str, &str)> = desc.variable_labels.iter()
+                .map(String::as_str)
+                .zip(labels.iter().copied())
+                .collect();
+            let buckets = self.requests_duration.buckets(labels[2]);
+            self.exemplars.record(&desc.fq_name, &series, buckets, duration, trace_id);
+        }
+    }
+
+    /// Attaches the fairing to `rocket` and also places a handle to it in
+    /// managed state so routes can reach the HTTP collectors.
+    pub fn attach(self, rocket: Rocket<Build>) -> Rocket<Build> {
+        rocket.manage(self.clone()).attach(self)
+    }
+}
+
+/// Size of the response body in bytes, from `Content-Length` when a responder set
+/// it and otherwise from the body itself. Streamed bodies have no known size and
+/// return `None`, so they don't contribute to the egress counter.
+async fn response_size(response: &mut Response<'_>) -> Option<usize> {
+    if let Some(length) = response.headers().get_one("Content-Length") {
+        return length.parse().ok();
+    }
+    response.body_mut().size().await
+}
+
+/// The `client_bucket` label of `request`: its client IP hashed into one of
+/// `buckets` values, so the label can't grow with the number of clients.
+/// `unknown` when the IP isn't known.
+fn client_bucket(request: &Request<'_>, buckets: u64) -> String {
+    let Some(ip) = request.client_ip() else {
+        return "unknown".to_string();
+    };
+    // DefaultHasher::new() uses fixed keys, so an IP keeps its bucket across restarts
+    let mut hasher = DefaultHasher::new();
+    ip.hash(&mut hasher);
+    (hasher.finish() % buckets).to_string()
+}
+
+/// The response's media type without parameters (`application/json`, not
+/// `application/json; charset=utf-8`), or `unknown` when there's no Content-Type.
+fn content_type_label(response: &Response<'_>) -> String {
+    response.content_type()
+        .map(|content_type| format!("{}/{}", content_type.top(), content_type.sub()).to_ascii_lowercase())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// The destination of `ACCESS_LOG=json` lines. Each line is written whole under
+/// the lock, so lines of concurrent requests don't interleave.
+#[derive(Clone)]
+pub struct AccessLogWriter(Arc<Mutex<Box<dyn Write + Send>>>);
+
+impl AccessLogWriter {
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        AccessLogWriter(Arc::new(Mutex::new(Box::new(writer))))
+    }
+
+    /// Appends to the file `ACCESS_LOG_FILE` names, so the lines aren't mixed
+    /// with Rocket's own output. Stdout when unset or when the file can't be
+    /// opened (logged).
+    pub fn from_env() -> Self {
+        let Some(path) = std::env::var_os("ACCESS_LOG_FILE").filter(|path| !path.is_empty()) else {
+            return AccessLogWriter::new(io::stdout());
+        };
+        match OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(file) => AccessLogWriter::new(file),
+            Err(e) => {
+                error!("could not open ACCESS_LOG_FILE {:?}: {}; writing the access log to stdout", path, e);
+                AccessLogWriter::new(io::stdout())
+            }
+        }
+    }
+
+    fn write_line(&self, line: &serde_json::Value) {
+        let mut line = line.to_string();
+        line.push('\n');
+        let mut writer = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Err(e) = writer.write_all(line.as_bytes()).and_then(|()| writer.flush()) {
+            warn!("could not write the access log: {}", e);
+        }
+    }
+}
+
+/// Writes the `ACCESS_LOG=json` line of a completed request, with the same
+/// method, `path` label and status its metrics were recorded under.
+fn write_access_log(
+    writer: &AccessLogWriter,
+    method: Method,
+    path: &str,
+    status: u16,
+    duration_ms: f64,
+    client_ip: Option<IpAddr>,
+) {
+    let timestamp = OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default();
+    let line = serde_json::json!({
+        "timestamp": timestamp,
+        "method": method.as_str(),
+        "path": path,
+        "status": status,
+        "duration_ms": duration_ms,
+        "client_ip": client_ip,
+    });
+    writer.write_line(&line);
+}
+
+/// False for requests to a route template in `METRICS_EXCLUDE_PATHS`, which
+/// no fairing records.
+pub fn is_instrumented(request: &Request<'_>) -> bool {
+    request.local_cache(|| {
+        let route = predicted_route(request);
+        let excluded = request.rocket().state::<PrometheusFairing>()
+            .is_some_and(|fairing| fairing.excluded_paths.iter().any(|path| path == route));
+        Instrumented(!excluded)
+    }).0
+}
+
+/// Route template that will most likely serve `request`, for use in
+/// `on_request` where Rocket hasn't routed the request yet. Mirrors Rocket's
+/// matching on method and path segments and picks the lowest rank; ignores
+/// format and query constraints.
+fn predicted_route<'r>(request: &'r Request<'_>) -> &'r str {
+    let segments: Vec<&str> = request.uri().path().segments().collect();
+    let method = request.method();
+    let route = request.rocket().routes()
+        .filter(|route| route.method == method || (method == Method::Head && route.method == Method::Get))
+        .filter(|route| template_matches(route.uri.path(), &segments))
+        .min_by_key(|route| (route.method != method, route.rank))
+        .map(|route| route.uri.path())
+        .unwrap_or("unmatched");
+    match label_value(route, MAX_PATH_LABEL_CHARS) {
+        Cow::Borrowed(route) => route,
+        Cow::Owned(route) => &request.local_cache(|| PredictedPathLabel(route)).0,
+    }
+}
+
+fn template_matches(template: &str, segments: &[&str]) -> bool {
+    let mut matched = 0;
+    for part in template.split('/').filter(|part| !part.is_empty()) {
+        let dynamic = part.starts_with('<') && part.ends_with('>');
+        if dynamic && part.ends_with("..>") {
+            return true;
+        }
+        match segments.get(matched) {
+            Some(segment) if dynamic || part == *segment => matched += 1,
+            _ => return false,
+        }
+    }
+    matched == segments.len()
+}
+
+/// The method the request arrived with, captured on the first call, which has
+/// to happen in an `on_request` hook: Rocket serves HEAD requests from GET
+/// routes by rewriting the method, so `request.method()` reads GET afterwards.
+pub fn request_method(request: &Request<'_>) -> Method {
+    request.local_cache(|| RequestMethod(request.method())).0
+}
+
+/// Returns the template of the route that handled `request` (e.g. `/items/<id>`),
+/// so concrete ids don't each become their own time series.
+pub fn normalized_path<'r>(request: &'r Request<'_>) -> &'r str {
+    let route = request.route()
+        .map(|route| route.uri.path())
+        .unwrap_or("unmatched");
+    match label_value(route, MAX_PATH_LABEL_CHARS) {
+        Cow::Borrowed(route) => route,
+        Cow::Owned(route) => &request.local_cache(|| PathLabel(route)).0,
+    }
+}
+
+/// The `http_version` label of a request. Rocket 0.5 reads the protocol version
+/// from hyper but doesn't expose it on `Request`, so every request is counted
+/// as `"1.1"` until Rocket makes the version available.
+fn http_version(_request: &Request<'_>) -> &'static str {
+    "1.1"
+}
+
+/// `value` made safe to use as a label value: control characters are dropped,
+/// U+FFFD (what lossy decoding leaves of invalid UTF-8 or percent-escapes)
+/// becomes `_` and anything past `max_chars` characters is cut off. Borrowed
+/// when `value` needed no change, which is the usual case.
+pub fn label_value(value: &str, max_chars: usize) -> Cow<'_, str> {
+    let clean = value.chars().count() <= max_chars
+        && !value.chars().any(|c| c.is_control() || c == char::REPLACEMENT_CHARACTER);
+    if clean {
+        return Cow::Borrowed(value);
+    }
+    Cow::Owned(value.chars()
+        .filter(|c| !c.is_control())
+        .map(|c| if c == char::REPLACEMENT_CHARACTER { '_' } else { c })
+        .take(max_chars)
+        .collect())
+}
+
+#[rocket::async_trait]
+impl Fairing for PrometheusFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Prometheus HTTP metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        if !is_instrumented(request) {
+            return;
+        }
+        let arrival = request.local_cache(|| RequestStart(Instant::now())).0;
+        if let Some(previous) = self.last_arrival.lock().unwrap().replace(arrival) {
+            self.request_interarrival.with_label_values(&[]).observe(arrival.saturating_duration_since(previous).as_secs_f64());
+        }
+        let method = request_method(request);
+        let body_size = request.headers().get_one("Content-Length")
+            .and_then(|length| length.parse().ok())
+            .unwrap_or(0);
+        request.local_cache(|| RequestBodySize(body_size));
+        request.local_cache(|| TraceId(exemplars::trace_id(request)));
+        let route = self.path_label(predicted_route(request));
+        let span = tracing::info_span!(
+            "request",
+            method = method.as_str(),
+            path = route,
+            status = Empty,
+            duration_ms = Empty,
+        );
+        request.local_cache(|| RequestSpan(span));
+        let by_path = self.requests_in_progress_by_path
+            .with_label_values(&[method.as_str(), route]);
+        request.local_cache(|| Some(InProgress::acquire(&self.requests_in_progress, by_path)));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if !is_instrumented(request) {
+            return;
+        }
+        let start = request.local_cache(|| RequestStart(Instant::now()));
+        let elapsed = start.0.elapsed();
+        let duration = elapsed.as_secs_f64();
+        let method = request_method(request);
+        let code = response.status().code;
+        let path = normalized_path(request);
+        let label = self.path_label(path);
+        if label != path {
+            self.cardinality_overflow_total.inc();
+        }
+        let path = label;
+        let content_type = content_type_label(response);
+        let client = client_bucket(request, self.client_buckets);
+        let auth = if is_authenticated(request) { "authenticated" } else { "anonymous" };
+        let response_size = response_size(response).await;
+        let _recording = self.recording.read().unwrap();
+        let series = self.series(method, code, path);
+        // Under sampling the duration series see only a share of the requests,
+        // so their counts undercount and quantiles become approximate
+        if self.duration_sample_rate >= 1.0 || fastrand::f64() < self.duration_sample_rate {
+            if let Some(trace_id) = &request.local_cache(|| TraceId(None)).0 {
+                let status = code.to_string();
+                self.observe_with_exemplar(&series, [method.as_str(), &status, path], duration, Some(trace_id));
+            } else {
+                series.duration.observe(duration);
+            }
+            series.duration_summary.observe(duration);
+        }
+        if elapsed > self.slow_request_threshold {
+            series.slow.inc();
+            warn!("slow request: {} {} took {} ms", method, path, elapsed.as_millis());
+        }
+        series.total.inc();
+        series.by_class.inc();
+        if let Some(legacy) = &series.legacy_total {
+            legacy.inc();
+        }
+        let body_size = request.local_cache(|| RequestBodySize(0));
+        series.request_size.inc_by(body_size.0 as f64);
+        self.responses_by_content_type_total.with_label_values(&[&content_type]).inc();
+        self.requests_by_client_total.with_label_values(&[&client]).inc();
+        self.requests_by_auth_total.with_label_values(&[auth]).inc();
+        self.requests_by_version_total.with_label_values(&[http_version(request)]).inc();
+        if let Some(size) = response_size {
+            series.response_size.inc_by(size as f64);
+        }
+        if let Some(in_progress) = request.local_cache(|| None::<InProgress>) {
+            in_progress.release();
+        }
+
+        let span = &request.local_cache(|| RequestSpan(Span::none())).0;
+        let duration_ms = duration * 1000.0;
+        if self.response_time_header {
+            response.set_raw_header("X-Response-Time-Ms", format!("{:.3}", duration_ms));
+        }
+        span.record("status", code);
+        span.record("duration_ms", duration_ms);
+        match self.access_log {
+            AccessLog::Tracing => span.in_scope(|| tracing::info!("request completed")),
+            AccessLog::Json => write_access_log(&self.access_log_writer, method, path, code, duration_ms, request.client_ip()),
+            AccessLog::Off => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn label_values_borrow_when_clean() {
+        assert!(matches!(label_value("/items/<id>", 64), Cow::Borrowed("/items/<id>")));
+    }
+
+    #[test]
+    fn label_values_drop_control_characters_and_truncate() {
+        assert_eq!(label_value("a\u{1}b\u{7f}c\n", 64), "abc");
+        assert_eq!(label_value("bad\u{fffd}byte", 64), "bad_byte");
+        assert_eq!(label_value("abcdef", 3), "abc");
+    }
+}
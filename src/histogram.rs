@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{Histogram, HistogramOpts, HistogramVec};
+
+use crate::config::route_bucket_key;
+
+/// A histogram family whose buckets may differ per `path` label. Routes with
+/// `METRICS_BUCKETS_<route>` get a vec of their own; the series of all vecs are
+/// exposed as the one family, which the exposition formats allow as long as
+/// each series is complete.
+#[derive(Clone)]
+pub struct RouteHistogramVec {
+    default: HistogramVec,
+    default_buckets: Vec<f64>,
+    /// By `route_bucket_key` of the route template, with their buckets.
+    overrides: HashMap<String, (HistogramVec, Vec<f64>)>,
+    path_index: usize,
+}
+
+impl RouteHistogramVec {
+    /// `opts` holds the default buckets; `label_names` must include `path`.
+    pub fn new(opts: HistogramOpts, label_names: &[&str], route_buckets: &HashMap<String, Vec<f64>>) -> prometheus::Result<Self> {
+        let path_index = label_names.iter().position(|name| *name == "path")
+            .ok_or_else(|| prometheus::Error::Msg("a route histogram needs a path label".to_string()))?;
+        let mut overrides = HashMap::new();
+        for (key, buckets) in route_buckets {
+            let vec = HistogramVec::new(opts.clone().buckets(buckets.clone()), label_names)?;
+            overrides.insert(key.clone(), (vec, buckets.clone()));
+        }
+        Ok(RouteHistogramVec {
+            default_buckets: opts.buckets.clone(),
+            default: HistogramVec::new(opts, label_names)?,
+            overrides,
+            path_index,
+        })
+    }
+
+    pub fn with_label_values(&self, values: &[&str]) -> Histogram {
+        let vec = match self.overrides.get(&route_bucket_key(values[self.path_index])) {
+            Some((vec, _)) => vec,
+            None => &self.default,
+        };
+        vec.with_label_values(values)
+    }
+
+    /// The upper bounds the series of `path` are bucketed by.
+    pub fn buckets(&self, path: &str) -> &[f64] {
+        match self.overrides.get(&route_bucket_key(path)) {
+            Some((_, buckets)) => buckets,
+            None => &self.default_buckets,
+        }
+    }
+
+    pub fn reset(&self) {
+        self.default.reset();
+        for (vec, _) in self.overrides.values() {
+            vec.reset();
+        }
+    }
+}
+
+impl Collector for RouteHistogramVec {
+    fn desc(&self) -> Vec<&Desc> {
+        self.default.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = self.default.collect();
+        for (vec, _) in self.overrides.values() {
+            for family in vec.collect() {
+                for metric in family.get_metric() {
+                    families[0].mut_metric().push(metric.clone());
+                }
+            }
+        }
+        families
+    }
+}
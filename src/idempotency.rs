@@ -0,0 +1,257 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::json::Json;
+use serde_json::Value;
+
+use crate::error::ApiError;
+use crate::registry::AppMetrics;
+
+const DEFAULT_MAX_KEYS: usize = 10_000;
+
+/// Longer `Idempotency-Key` values are rejected with 400.
+const MAX_KEY_CHARS: usize = 255;
+
+/// The request's `Idempotency-Key` header, `None` when it has none, along with
+/// the managed `IdempotencyKeys` it is looked up in. Owns both, so the creation
+/// can be moved to a blocking thread.
+pub struct Idempotency {
+    keys: IdempotencyKeys,
+    key: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Idempotency {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(keys) = request.rocket().state::<IdempotencyKeys>() else {
+            return Outcome::Error((Status::InternalServerError, ()));
+        };
+        let key = request.headers().get_one("Idempotency-Key")
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(str::to_string);
+        Outcome::Success(Idempotency { keys: keys.clone(), key })
+    }
+}
+
+/// Answer of `POST /items`: the new item, or the response a request with the
+/// same `Idempotency-Key` got, replayed with 200.
+#[derive(Responder)]
+pub enum ItemCreated {
+    #[response(status = 201)]
+    New(Json<Value>, Header<'static>),
+    #[response(status = 200)]
+    Replayed(Json<Value>, Header<'static>),
+}
+
+/// A key's creation, once it has started.
+enum Entry {
+    /// The first request with the key is still creating its item.
+    InFlight,
+    /// The id and body the item was created with.
+    Created(usize, Value),
+}
+
+/// The least recently used created keys go first once `capacity` is reached;
+/// in-flight keys aren't in `order` and can't be evicted.
+struct Entries {
+    responses: HashMap<String, Entry>,
+    order: VecDeque<String>,
+}
+
+/// Responses of item creations by `Idempotency-Key`, keeping the latest
+/// `IDEMPOTENCY_KEYS_MAX` keys. Only successful creations are remembered, so a
+/// retry after an error tries again. The body of a replayed request isn't
+/// compared with the original one. Clones share the keys.
+#[derive(Clone)]
+pub struct IdempotencyKeys {
+    capacity: usize,
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl IdempotencyKeys {
+    pub fn new(capacity: usize) -> Self {
+        IdempotencyKeys {
+            capacity,
+            entries: Arc::new(Mutex::new(Entries { responses: HashMap::new(), order: VecDeque::new() })),
+        }
+    }
+
+    /// Reads `IDEMPOTENCY_KEYS_MAX`; unset or invalid values (the latter
+    /// logged) keep 10000 keys.
+    pub fn from_env() -> Self {
+        let capacity = match std::env::var("IDEMPOTENCY_KEYS_MAX") {
+            Ok(value) => value.parse::<usize>().ok().filter(|max| *max > 0).unwrap_or_else(|| {
+                warn!("IDEMPOTENCY_KEYS_MAX={:?} is not a positive number; keeping 10000 keys", value);
+                DEFAULT_MAX_KEYS
+            }),
+            Err(_) => DEFAULT_MAX_KEYS,
+        };
+        IdempotencyKeys::new(capacity)
+    }
+
+    /// Every update of the entries completes before anything that can panic,
+    /// so a poisoned lock still guards consistent entries and is cleared.
+    fn lock(&self) -> MutexGuard<'_, Entries> {
+        self.entries.lock().unwrap_or_else(|poisoned| {
+            self.entries.clear_poison();
+            error!("recovered the idempotency keys lock after a handler panicked while holding it");
+            poisoned.into_inner()
+        })
+    }
+}
+
+/// Removes the in-flight entry of `key` when dropped without `created` having
+/// replaced it, so a creation that failed or panicked can be retried with the
+/// same key.
+struct InFlight<'k> {
+    keys: &'k IdempotencyKeys,
+    key: &'k str,
+}
+
+impl InFlight<'_> {
+    fn created(&self, id: usize, body: Value) {
+        let mut entries = self.keys.lock();
+        if entries.order.len() >= self.keys.capacity {
+            if let Some(oldest) = entries.order.pop_front() {
+                entries.responses.remove(&oldest);
+            }
+        }
+        entries.responses.insert(self.key.to_string(), Entry::Created(id, body));
+        entries.order.push_back(self.key.to_string());
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut entries = self.keys.lock();
+        if matches!(entries.responses.get(self.key), Some(Entry::InFlight)) {
+            entries.responses.remove(self.key);
+        }
+    }
+}
+
+impl Idempotency {
+    /// Runs `create`, which returns the new item's id and body, unless the key
+    /// was already used; then the remembered response is replayed and counted
+    /// in `items_idempotent_replays_total`. The key is marked in flight while
+    /// `create` runs, without holding the lock, and a concurrent retry with it
+    /// is answered 409 instead of creating a second item.
+    pub fn create_once(
+        &self,
+        metrics: &AppMetrics,
+        create: impl FnOnce() -> Result<(usize, Value), ApiError>,
+    ) -> Result<ItemCreated, ApiError> {
+        let Some(key) = &self.key else {
+            let (id, body) = create()?;
+            return Ok(ItemCreated::New(Json(body), location(id)));
+        };
+        if key.chars().count() > MAX_KEY_CHARS {
+            return Err(ApiError::new(
+                Status::BadRequest,
+                format!("Idempotency-Key must be at most {} characters", MAX_KEY_CHARS),
+            ));
+        }
+        let mut entries = self.keys.lock();
+        match entries.responses.get(key) {
+            Some(Entry::Created(id, body)) => {
+                let (id, body) = (*id, body.clone());
+                if let Some(position) = entries.order.iter().position(|used| used == key) {
+                    let used = entries.order.remove(position).unwrap();
+                    entries.order.push_back(used);
+                }
+                metrics.items_idempotent_replays_total.inc();
+                return Ok(ItemCreated::Replayed(Json(body), location(id)));
+            }
+            Some(Entry::InFlight) => {
+                return Err(ApiError::new(
+                    Status::Conflict,
+                    "a request with this Idempotency-Key is still in progress; retry it once that one completed",
+                ));
+            }
+            None => {
+                entries.responses.insert(key.clone(), Entry::InFlight);
+            }
+        }
+        drop(entries);
+        let in_flight = InFlight { keys: &self.keys, key };
+        let (id, body) = create()?;
+        in_flight.created(id, body.clone());
+        Ok(ItemCreated::New(Json(body), location(id)))
+    }
+}
+
+fn location(id: usize) -> Header<'static> {
+    Header::new("Location", format!("/items/{}", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::config::MetricsConfig;
+    use crate::registry::build_registry;
+
+    fn with_key(keys: &IdempotencyKeys, key: &str) -> Idempotency {
+        Idempotency { keys: keys.clone(), key: Some(key.to_string()) }
+    }
+
+    #[test]
+    fn concurrent_retry_is_refused_while_the_first_creates() {
+        let keys = IdempotencyKeys::new(10);
+        let metrics = build_registry(&MetricsConfig::default()).unwrap().app;
+        let (started, creating) = mpsc::channel();
+        let (finish, finished) = mpsc::channel::<()>();
+        thread::scope(|scope| {
+            let first = scope.spawn(|| with_key(&keys, "k").create_once(&metrics, move || {
+                started.send(()).unwrap();
+                finished.recv().unwrap();
+                Ok((1, json!({ "item_id": 1 })))
+            }));
+            creating.recv().unwrap();
+            // The lock isn't held while the first request creates its item.
+            let retry = with_key(&keys, "k").create_once(&metrics, || panic!("created twice"));
+            assert_eq!(retry.err().unwrap().status, Status::Conflict);
+            let other = with_key(&keys, "other").create_once(&metrics, || Ok((2, json!({ "item_id": 2 }))));
+            assert!(matches!(other, Ok(ItemCreated::New(..))));
+            finish.send(()).unwrap();
+            assert!(matches!(first.join().unwrap(), Ok(ItemCreated::New(..))));
+        });
+        let replay = with_key(&keys, "k").create_once(&metrics, || panic!("created twice"));
+        assert!(matches!(replay, Ok(ItemCreated::Replayed(Json(body), _)) if body["item_id"] == 1));
+    }
+
+    #[test]
+    fn a_panicking_creation_frees_its_key() {
+        let keys = IdempotencyKeys::new(10);
+        let metrics = build_registry(&MetricsConfig::default()).unwrap().app;
+        let panicked = thread::scope(|scope| {
+            scope.spawn(|| with_key(&keys, "k").create_once(&metrics, || panic!("handler failure"))).join()
+        });
+        assert!(panicked.is_err());
+        let retry = with_key(&keys, "k").create_once(&metrics, || Ok((1, json!({ "item_id": 1 }))));
+        assert!(matches!(retry, Ok(ItemCreated::New(..))));
+    }
+
+    #[test]
+    fn recovers_from_a_poisoned_lock() {
+        let keys = IdempotencyKeys::new(10);
+        let metrics = build_registry(&MetricsConfig::default()).unwrap().app;
+        let _ = thread::scope(|scope| scope.spawn(|| {
+            let _entries = keys.entries.lock().unwrap();
+            panic!("poisoning the idempotency keys lock");
+        }).join());
+        assert!(keys.entries.is_poisoned());
+        let created = with_key(&keys, "k").create_once(&metrics, || Ok((1, json!({ "item_id": 1 }))));
+        assert!(matches!(created, Ok(ItemCreated::New(..))));
+        assert!(!keys.entries.is_poisoned());
+    }
+}
@@ -1,181 +1,1358 @@
 #[macro_use] extern crate rocket;
 #[macro_use] extern crate lazy_static;
 
-use std::collections::HashMap;
-use std::sync::Mutex;
-use std::cell::RefCell;
-use rocket::State;
-use rocket::serde::{Serialize, Deserialize, json::Json};
-use rocket::request::{self, FromRequest, Outcome, Request};
-use rocket::response::status::NotFound;
+mod auth;
+mod body;
+mod config;
+mod cors;
+mod error;
+mod etag;
+mod exemplars;
+mod exposition;
+mod fairing;
+mod histogram;
+mod idempotency;
+mod maintenance;
+mod registry;
+mod openmetrics;
+mod otel;
+mod persistence;
+mod push;
+mod ratelimit;
+mod statsd;
+mod summary;
+mod system;
+mod timeout;
+
+#[cfg(test)]
+mod tests;
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+use rocket::serde::{Deserialize, json::Json};
+use rocket::request::{self, FromRequest};
+use rocket::{Build, Request, Rocket, State};
+use rocket::fairing::AdHoc;
+use rocket::data::Limits;
+use rocket::http::{Header, Status};
+use rocket::response::status;
 use serde_json::json;
-use prometheus::{Registry, Gauge, HistogramOpts, Encoder, TextEncoder, CounterVec, HistogramVec};
-use sys_info::{loadavg, mem_info};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{Registry, Encoder, Gauge, HistogramTimer, Opts, TextEncoder};
+use tracing_subscriber::EnvFilter;
+
+use auth::{MetricsAuth, MetricsToken};
+use body::{CheckedJson, JsonRejection};
+use config::{AccessLog, MetricsConfig};
+use cors::CorsFairing;
+use error::ApiError;
+use etag::{IfNoneMatch, Tagged};
+use exposition::{AcceptsGzip, CatalogEntry, FamilyJson, MetricsBody, MetricsFormat};
+use fairing::{AccessLogWriter, PrometheusFairing, RouteTotals};
+use idempotency::{Idempotency, IdempotencyKeys, ItemCreated};
+use maintenance::{Maintenance, NotInMaintenance};
+use registry::AppMetrics;
+use otel::OtelFairing;
+use persistence::{Item, ItemsFile, StoredItems};
+use ratelimit::{RateLimiter, WithinRateLimit};
+use statsd::StatsdFairing;
+use system::{HostSystem, SystemInfo, SystemInfoProvider};
 
 lazy_static! {
-    static ref REGISTRY: Registry = Registry::new();
-    static ref HTTP_REQUESTS_TOTAL: CounterVec = CounterVec::new(
-        prometheus::opts!("http_request_total", "Total HTTP Requests"),
-        &["method", "status", "path"]
-    ).unwrap();
-    static ref HTTP_REQUESTS_DURATION: HistogramVec = HistogramVec::new(
-        HistogramOpts::new("http_request_duration_seconds", "HTTP Request Duration"),
-        &["method", "status", "path"]
-    ).unwrap();
-    static ref HTTP_REQUESTS_IN_PROGRESS: Gauge = Gauge::new("http_requests_in_progress", "Number of HTTP requests in progress").unwrap();
-    static ref PROCESS_CPU_USAGE: Gauge = Gauge::new("process_cpu_usage", "The recent cpu usage for the process").unwrap();
-    static ref MEMORY_USED_BYTES: Gauge = Gauge::new("memory_used_bytes", "The amount of used memory").unwrap();
-    static ref THREADS_LIVE: Gauge = Gauge::new("threads_live", "The current number of live threads").unwrap();
+    static ref START_TIME: std::time::Instant = std::time::Instant::now();
 }
 
-thread_local! {
-    static REQUEST_DATA: RefCell<Option<(String, String, String)>> = RefCell::new(None);
+/// A guard of the items lock that records in `items_lock_hold_seconds` how long
+/// it was held once it is dropped. `guard` is declared first so the lock is
+/// released before the timer stops.
+struct Held<G> {
+    guard: G,
+    _timer: HistogramTimer,
 }
 
-type Items = Mutex<HashMap<usize, String>>;
+impl<G> Held<G> {
+    fn new(guard: G, metrics: &AppMetrics) -> Self {
+        Held { guard, _timer: metrics.items_lock_hold_seconds.start_timer() }
+    }
+}
+
+impl<G: Deref> Deref for Held<G> {
+    type Target = G::Target;
 
-#[derive(Serialize, Deserialize)]
-struct Item {
-    name: String,
+    fn deref(&self) -> &G::Target {
+        &self.guard
+    }
 }
 
-struct Timer {
-    start: std::time::Instant,
+impl<G: DerefMut> DerefMut for Held<G> {
+    fn deref_mut(&mut self) -> &mut G::Target {
+        &mut self.guard
+    }
 }
 
-#[rocket::async_trait]
-impl<'r> FromRequest<'r> for Timer {
-    type Error = ();
+/// `items_oldest_age_seconds`, worked out from the store whenever the registry
+/// is gathered. Items without a creation time are left out; an empty store
+/// reports 0.
+#[derive(Clone)]
+struct OldestItemAge {
+    items: Items,
+    gauge: Gauge,
+}
 
-    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        let method = request.method().to_string();
-        let path = request.uri().path().to_string();
-        REQUEST_DATA.with(|data| {
-            *data.borrow_mut() = Some((method, path, String::new()));
-        });
-        HTTP_REQUESTS_IN_PROGRESS.inc();
-        Outcome::Success(Timer {
-            start: std::time::Instant::now(),
-        })
+impl OldestItemAge {
+    fn new(items: Items, namespace: &str) -> prometheus::Result<Self> {
+        let gauge = Gauge::with_opts(
+            Opts::new("items_oldest_age_seconds", "Age of the oldest stored item, 0 when the store is empty").namespace(namespace)
+        )?;
+        Ok(OldestItemAge { items, gauge })
     }
 }
 
-impl Drop for Timer {
-    fn drop(&mut self) {
-        let duration = self.start.elapsed().as_secs_f64();
-        REQUEST_DATA.with(|data| {
-            if let Some((method, path, status)) = data.borrow_mut().as_ref() {
-                let status = if status.is_empty() { "200" } else { status };
-                HTTP_REQUESTS_DURATION.with_label_values(&[method, status, path]).observe(duration);
-                HTTP_REQUESTS_TOTAL.with_label_values(&[method, status, path]).inc();
-            }
-        });
-        HTTP_REQUESTS_IN_PROGRESS.dec();
+impl Collector for OldestItemAge {
+    fn desc(&self) -> Vec<&Desc> {
+        self.gauge.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let map = self.items.map.read().unwrap_or_else(PoisonError::into_inner);
+        let oldest = map.values().filter_map(|item| item.created_at).reduce(f64::min);
+        drop(map);
+        self.gauge.set(oldest.map_or(0.0, |created_at| (persistence::unix_now() - created_at).max(0.0)));
+        self.gauge.collect()
     }
 }
 
-#[get("/")]
-fn index(_timer: Timer) -> &'static str {
-    "Hello, world!"
+/// The item store. Lookups and listings share the lock and run concurrently;
+/// only mutations take it exclusively. Clones share the store, so the admin
+/// server's `/readyz` sees the same lock.
+#[derive(Clone)]
+struct Items {
+    map: Arc<RwLock<HashMap<usize, Item>>>,
+    next_id: Arc<AtomicUsize>,
+}
+
+impl Items {
+    /// Resumes from a loaded store. `next_id` is never below the largest stored
+    /// id + 1, in case the file was edited by hand.
+    fn new(stored: StoredItems) -> Self {
+        let next_id = stored.items.keys().max().map_or(1, |id| id + 1).max(stored.next_id);
+        Items { map: Arc::new(RwLock::new(stored.items)), next_id: Arc::new(AtomicUsize::new(next_id)) }
+    }
+
+    /// A fresh id. The counter only grows, so ids of deleted items are not
+    /// handed out again and concurrent creates never collide.
+    fn next_id(&self) -> usize {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Takes the lock for reading, recording the wait in `items_lock_wait_seconds`.
+    fn read(&self, metrics: &AppMetrics) -> Result<Held<RwLockReadGuard<'_, HashMap<usize, Item>>>, ApiError> {
+        let timer = metrics.items_lock_wait_seconds.start_timer();
+        let map = self.map.read().map_err(|poisoned| self.recover(poisoned.into_inner(), metrics));
+        timer.observe_duration();
+        map.map(|guard| Held::new(guard, metrics))
+    }
+
+    /// Takes the lock for writing, recording the wait in `items_lock_wait_seconds`.
+    fn write(&self, metrics: &AppMetrics) -> Result<Held<RwLockWriteGuard<'_, HashMap<usize, Item>>>, ApiError> {
+        let timer = metrics.items_lock_wait_seconds.start_timer();
+        let map = self.map.write().map_err(|poisoned| self.recover(poisoned.into_inner(), metrics));
+        timer.observe_duration();
+        map.map(|guard| Held::new(guard, metrics))
+    }
+
+    /// A handler panicked while holding the lock. Every mutation completes its
+    /// map update before anything that can panic, so the map is still
+    /// consistent: the poison is cleared for the requests after this one, which
+    /// fails with a 500 counted in `items_lock_poisoned_total`.
+    fn recover<G>(&self, guard: G, metrics: &AppMetrics) -> ApiError {
+        drop(guard);
+        self.map.clear_poison();
+        metrics.items_lock_poisoned_total.inc();
+        error!("recovered the items store lock after a handler panicked while holding it");
+        ApiError::new(Status::InternalServerError, "the item store was interrupted by an earlier failure; retry the request")
+    }
+
+    fn upcoming_id(&self) -> usize {
+        self.next_id.load(Ordering::Relaxed)
+    }
 }
 
-#[post("/items", data = "<item>")]
-fn create_item(item: Json<Item>, items: &State<Items>, _timer: Timer) -> Json<serde_json::Value> {
-    let mut items = items.lock().unwrap();
-    let id = items.len() + 1;
-    items.insert(id, item.name.clone());
-    Json(json!({
+/// Body of `PATCH /items/<id>`: fields left out keep their current value.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ItemPatch {
+    name: Option<String>,
+    description: Option<String>,
+    tags: Option<Vec<String>>,
+}
+
+/// An item as the API returns it, with `status` added for mutations.
+fn item_json(id: usize, item: &Item, status: Option<&str>) -> serde_json::Value {
+    let mut body = json!({
         "item_id": id,
         "name": item.name,
-        "status": "created"
-    }))
+        "description": item.description,
+        "tags": item.tags
+    });
+    if let Some(status) = status {
+        body["status"] = json!(status);
+    }
+    body
 }
 
-#[get("/items/<id>")]
-fn read_item(id: usize, items: &State<Items>, _timer: Timer) -> Result<Json<serde_json::Value>, NotFound<String>> {
-    let items = items.lock().unwrap();
-    items.get(&id)
-        .map(|name| {
-            Json(json!({
-                "item_id": id,
-                "name": name
-            }))
-        })
-        .ok_or_else(|| {
-            REQUEST_DATA.with(|data| {
-                if let Some((_, _, status)) = data.borrow_mut().as_mut() {
-                    *status = "404".to_string();
-                }
-            });
-            NotFound(format!("Item with id {} not found", id))
+const MAX_NAME_CHARS: usize = 255;
+
+/// Rejects empty names and names over `MAX_NAME_CHARS`, counting the rejection
+/// against the route template `path`.
+fn check_name(name: &str, path: &str, metrics: &AppMetrics) -> Result<(), String> {
+    let problem = if name.is_empty() {
+        "name must not be empty".to_string()
+    } else if name.chars().count() > MAX_NAME_CHARS {
+        format!("name must be at most {} characters", MAX_NAME_CHARS)
+    } else {
+        return Ok(());
+    };
+    metrics.http_validation_errors_total.with_label_values(&[path]).inc();
+    Err(problem)
+}
+
+fn validate_name(name: &str, path: &str, metrics: &AppMetrics) -> Result<(), ApiError> {
+    check_name(name, path, metrics).map_err(|problem| ApiError::new(Status::UnprocessableEntity, problem))
+}
+
+/// Rough size of the item map in bytes: each entry's key and `Item` plus the
+/// heap bytes of its strings. Ignores the map's own spare capacity.
+fn store_bytes(map: &HashMap<usize, Item>) -> usize {
+    map.values()
+        .map(|item| {
+            let strings = item.name.len()
+                + item.description.as_ref().map_or(0, String::len)
+                + item.tags.iter().map(|tag| mem::size_of::<String>() + tag.len()).sum::<usize>();
+            mem::size_of::<usize>() + mem::size_of::<Item>() + strings
         })
+        .sum()
 }
 
-#[put("/items/<id>", data = "<item>")]
-fn update_item(id: usize, item: Json<Item>, items: &State<Items>, _timer: Timer) -> Result<Json<serde_json::Value>, NotFound<String>> {
-    let mut items = items.lock().unwrap();
-    if let Some(name) = items.get_mut(&id) {
-        *name = item.name.clone();
+/// Persists the store after a mutation and refreshes `items_store_bytes`.
+/// Failures are logged; the in-memory map stays authoritative and the next
+/// mutation retries the write.
+fn save_items(items_file: &ItemsFile, items: &Items, map: &HashMap<usize, Item>, metrics: &AppMetrics) {
+    metrics.items_store_bytes.set(store_bytes(map) as f64);
+    let result = items_file.save(map, items.upcoming_id());
+    PERSISTENCE_FAILED.store(result.is_err(), Ordering::Relaxed);
+    // The caller holds the items lock, so it isn't poisoned
+    metrics.service_ready.set(if result.is_ok() { 1.0 } else { 0.0 });
+    if let Err(e) = result {
+        error!("could not persist items: {}", e);
+    }
+}
+
+/// Set while the last write of `ITEMS_FILE` failed; makes `/readyz` fail until
+/// a later write succeeds.
+static PERSISTENCE_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Runs an item handler's store work, which waits for the items lock and
+/// writes `ITEMS_FILE`, on the blocking pool. The handler awaits it rather
+/// than blocking the thread polling it, so `REQUEST_TIMEOUT_MS` can give up on
+/// it; work given up on still runs to completion, only its answer is dropped.
+/// A panic is resumed in the handler, which Rocket answers with a 500.
+async fn on_blocking_thread<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> T {
+    match rocket::tokio::task::spawn_blocking(work).await {
+        Ok(value) => value,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+#[get("/")]
+fn index(_rate: WithinRateLimit) -> &'static str {
+    "Hello, world!"
+}
+
+/// Liveness probe. Not counted in the request metrics, see `PrometheusFairing`.
+#[get("/healthz")]
+fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Readiness probe: fails with 503 once a panic has poisoned the items lock or
+/// while the items file can't be written. Also updates `service_ready`.
+#[get("/readyz")]
+fn readyz(items: &State<Items>, metrics: &State<AppMetrics>) -> (Status, &'static str) {
+    let readiness = if items.map.read().is_err() {
+        (Status::ServiceUnavailable, "items store lock is poisoned")
+    } else if PERSISTENCE_FAILED.load(Ordering::Relaxed) {
+        (Status::ServiceUnavailable, "items file could not be written")
+    } else {
+        (Status::Ok, "ok")
+    };
+    metrics.service_ready.set(if readiness.0 == Status::Ok { 1.0 } else { 0.0 });
+    readiness
+}
+
+const DEFAULT_PAGE_LIMIT: usize = 50;
+const MAX_PAGE_LIMIT: usize = 500;
+
+/// Lists items ordered by id, one page at a time. `limit` is clamped to
+/// `1..=MAX_PAGE_LIMIT`; an `offset` past the end yields an empty page.
+#[get("/items?<limit>&<offset>")]
+async fn list_items(
+    limit: Option<usize>,
+    offset: Option<usize>,
+    _open: NotInMaintenance,
+    _rate: WithinRateLimit,
+    items: &State<Items>,
+    metrics: &State<AppMetrics>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT);
+    let offset = offset.unwrap_or(0);
+    let (items, metrics) = (items.inner().clone(), metrics.inner().clone());
+    on_blocking_thread(move || {
+        let items = items.read(&metrics)?;
+        let mut ids: Vec<&usize> = items.keys().collect();
+        ids.sort();
+        let page: Vec<serde_json::Value> = ids.into_iter()
+            .skip(offset)
+            .take(limit)
+            .map(|id| item_json(*id, &items[id], None))
+            .collect();
+        metrics.items_list_page_size.observe(page.len() as f64);
         Ok(Json(json!({
-            "item_id": id,
-            "name": name,
-            "status": "updated"
+            "items": page,
+            "total": items.len(),
+            "limit": limit,
+            "offset": offset
         })))
-    } else {
-        REQUEST_DATA.with(|data| {
-            if let Some((_, _, status)) = data.borrow_mut().as_mut() {
-                *status = "404".to_string();
+    }).await
+}
+
+/// The number of stored items as a bare integer, for probes that just want the
+/// count.
+#[get("/items/count")]
+async fn count_items(_open: NotInMaintenance, _rate: WithinRateLimit, items: &State<Items>, metrics: &State<AppMetrics>) -> Result<String, ApiError> {
+    let (items, metrics) = (items.inner().clone(), metrics.inner().clone());
+    on_blocking_thread(move || Ok(items.read(&metrics)?.len().to_string())).await
+}
+
+/// Items whose name contains `q`, ignoring case, ordered by id. An empty or
+/// missing `q` is a 400.
+#[get("/items/search?<q>")]
+async fn search_items(
+    q: Option<&str>,
+    _open: NotInMaintenance,
+    _rate: WithinRateLimit,
+    items: &State<Items>,
+    metrics: &State<AppMetrics>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let query = q.filter(|q| !q.is_empty())
+        .ok_or_else(|| ApiError::new(Status::BadRequest, "q must not be empty"))?
+        .to_lowercase();
+    let (items, metrics) = (items.inner().clone(), metrics.inner().clone());
+    on_blocking_thread(move || {
+        let items = items.read(&metrics)?;
+        let mut ids: Vec<&usize> = items.keys()
+            .filter(|id| items[id].name.to_lowercase().contains(&query))
+            .collect();
+        ids.sort();
+        let results: Vec<serde_json::Value> = ids.into_iter()
+            .map(|id| item_json(*id, &items[id], None))
+            .collect();
+        metrics.items_search_total.inc();
+        metrics.items_search_results.observe(results.len() as f64);
+        Ok(Json(json!({
+            "items": results,
+            "total": results.len()
+        })))
+    }).await
+}
+
+/// Name prefix for items created without a body, from `DEFAULT_ITEM_NAME`
+/// (default `untitled`); the id is appended, e.g. `untitled-7`.
+struct DefaultItemName(String);
+
+impl DefaultItemName {
+    /// Reads `DEFAULT_ITEM_NAME`. A prefix leaving no room for the id within
+    /// `MAX_NAME_CHARS` is logged and replaced by `untitled`, so generated
+    /// names are always valid and no id is taken for a name that isn't.
+    fn from_env() -> Self {
+        match std::env::var("DEFAULT_ITEM_NAME") {
+            Ok(prefix) if format!("{}-{}", prefix, usize::MAX).chars().count() <= MAX_NAME_CHARS => DefaultItemName(prefix),
+            Ok(prefix) => {
+                warn!("DEFAULT_ITEM_NAME={:?} leaves no room for the id within {} characters; using untitled", prefix, MAX_NAME_CHARS);
+                DefaultItemName("untitled".to_string())
             }
-        });
-        Err(NotFound(format!("Item with id {} not found", id)))
+            Err(_) => DefaultItemName("untitled".to_string()),
+        }
+    }
+}
+
+/// Request guard that succeeds only for requests without a body and forwards
+/// all others.
+struct EmptyBody;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for EmptyBody {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        let empty = match headers.get_one("Content-Length") {
+            Some(length) => length.trim() == "0",
+            None => !headers.contains("Transfer-Encoding"),
+        };
+        if empty {
+            request::Outcome::Success(EmptyBody)
+        } else {
+            request::Outcome::Forward(Status::BadRequest)
+        }
+    }
+}
+
+/// Stores `item` under `id` and returns the body of the 201 Created answer.
+fn insert_item(
+    id: usize,
+    mut item: Item,
+    items: &Items,
+    items_file: &ItemsFile,
+    metrics: &AppMetrics,
+) -> Result<(usize, serde_json::Value), ApiError> {
+    item.created_at = Some(persistence::unix_now());
+    let mut map = items.write(metrics)?;
+    map.insert(id, item.clone());
+    metrics.items_count.set(map.len() as f64);
+    save_items(items_file, items, &map, metrics);
+    metrics.item_name_length_chars.observe(item.name.chars().count() as f64);
+    if !item.tags.is_empty() {
+        metrics.items_tagged_total.inc();
     }
+    Ok((id, item_json(id, &item, Some("created"))))
+}
+
+#[post("/items", data = "<item>", rank = 2)]
+async fn create_item(
+    _open: NotInMaintenance,
+    _rate: WithinRateLimit,
+    idempotency: Idempotency,
+    item: CheckedJson<Item>,
+    items: &State<Items>,
+    items_file: &State<ItemsFile>,
+    metrics: &State<AppMetrics>,
+) -> Result<ItemCreated, ApiError> {
+    let (items, items_file, metrics) = (items.inner().clone(), items_file.inner().clone(), metrics.inner().clone());
+    on_blocking_thread(move || idempotency.create_once(&metrics, || {
+        validate_name(&item.name, "/items", &metrics)?;
+        insert_item(items.next_id(), item.into_inner(), &items, &items_file, &metrics)
+    })).await
+}
+
+/// `POST /items` without a body: creates an item named after
+/// `DEFAULT_ITEM_NAME` and its id, counted in `items_autonamed_total`.
+#[post("/items", rank = 1)]
+#[allow(clippy::too_many_arguments)]
+async fn create_item_autonamed(
+    _empty: EmptyBody,
+    _open: NotInMaintenance,
+    _rate: WithinRateLimit,
+    idempotency: Idempotency,
+    default_name: &State<DefaultItemName>,
+    items: &State<Items>,
+    items_file: &State<ItemsFile>,
+    metrics: &State<AppMetrics>,
+) -> Result<ItemCreated, ApiError> {
+    let default_name = default_name.0.clone();
+    let (items, items_file, metrics) = (items.inner().clone(), items_file.inner().clone(), metrics.inner().clone());
+    on_blocking_thread(move || idempotency.create_once(&metrics, || {
+        let id = items.next_id();
+        let item = Item { name: format!("{}-{}", default_name, id), description: None, tags: Vec::new(), created_at: None };
+        metrics.items_autonamed_total.inc();
+        insert_item(id, item, &items, &items_file, &metrics)
+    })).await
+}
+
+/// Creates each valid item in the batch. Answers 200 when all were created and
+/// 207 Multi-Status when some were rejected, with one result per input element.
+#[post("/items/bulk", data = "<batch>")]
+async fn create_items_bulk(
+    _open: NotInMaintenance,
+    _rate: WithinRateLimit,
+    batch: CheckedJson<Vec<Item>>,
+    items: &State<Items>,
+    items_file: &State<ItemsFile>,
+    metrics: &State<AppMetrics>,
+) -> Result<status::Custom<Json<serde_json::Value>>, ApiError> {
+    let (items, items_file, metrics) = (items.inner().clone(), items_file.inner().clone(), metrics.inner().clone());
+    on_blocking_thread(move || {
+        let mut map = items.write(&metrics)?;
+        let mut created = 0;
+        let results: Vec<serde_json::Value> = batch.iter().enumerate()
+            .map(|(index, item)| match check_name(&item.name, "/items/bulk", &metrics) {
+                Ok(()) => {
+                    let id = items.next_id();
+                    map.insert(id, Item { created_at: Some(persistence::unix_now()), ..item.clone() });
+                    metrics.items_count.set(map.len() as f64);
+                    metrics.item_name_length_chars.observe(item.name.chars().count() as f64);
+                    if !item.tags.is_empty() {
+                        metrics.items_tagged_total.inc();
+                    }
+                    created += 1;
+                    let mut result = item_json(id, item, Some("created"));
+                    result["index"] = json!(index);
+                    result
+                }
+                Err(problem) => json!({ "index": index, "status": "rejected", "error": problem }),
+            })
+            .collect();
+        if created > 0 {
+            save_items(&items_file, &items, &map, &metrics);
+        }
+
+        let failed = results.len() - created;
+        let status = if failed == 0 { Status::Ok } else { Status::MultiStatus };
+        Ok(status::Custom(status, Json(json!({
+            "created": created,
+            "failed": failed,
+            "results": results
+        }))))
+    }).await
+}
+
+/// Tags the item with a weak ETag of its fields and answers 304 Not Modified,
+/// counted in `http_cache_hits_total`, when `If-None-Match` already has it.
+#[get("/items/<id>")]
+async fn read_item(
+    id: usize,
+    _open: NotInMaintenance,
+    _rate: WithinRateLimit,
+    if_none_match: IfNoneMatch,
+    items: &State<Items>,
+    metrics: &State<AppMetrics>,
+) -> Result<Tagged, ApiError> {
+    let (items, metrics) = (items.inner().clone(), metrics.inner().clone());
+    on_blocking_thread(move || {
+        let items = items.read(&metrics)?;
+        let item = items.get(&id).ok_or_else(|| ApiError::item_not_found(id))?;
+        let etag = etag::weak_etag(&serde_json::to_string(item).unwrap());
+        if if_none_match.matches(&etag) {
+            metrics.http_cache_hits_total.inc();
+            return Ok(Tagged::NotModified((), Header::new("ETag", etag)));
+        }
+        Ok(Tagged::Fresh(Json(item_json(id, item, None)), Header::new("ETag", etag)))
+    }).await
+}
+
+#[put("/items/<id>", data = "<item>")]
+async fn update_item(
+    id: usize,
+    _open: NotInMaintenance,
+    _rate: WithinRateLimit,
+    item: CheckedJson<Item>,
+    items: &State<Items>,
+    items_file: &State<ItemsFile>,
+    metrics: &State<AppMetrics>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    validate_name(&item.name, "/items/<id>", metrics)?;
+    let (items, items_file, metrics) = (items.inner().clone(), items_file.inner().clone(), metrics.inner().clone());
+    on_blocking_thread(move || {
+        let mut map = items.write(&metrics)?;
+        if let Some(stored) = map.get_mut(&id) {
+            *stored = Item { created_at: stored.created_at, ..item.0.clone() };
+            save_items(&items_file, &items, &map, &metrics);
+            metrics.item_name_length_chars.observe(item.name.chars().count() as f64);
+            Ok(Json(item_json(id, &item, Some("updated"))))
+        } else {
+            Err(ApiError::item_not_found(id))
+        }
+    }).await
+}
+
+/// Updates only the fields present in the body and returns the merged item.
+#[patch("/items/<id>", data = "<patch>")]
+async fn patch_item(
+    id: usize,
+    _open: NotInMaintenance,
+    _rate: WithinRateLimit,
+    patch: CheckedJson<ItemPatch>,
+    items: &State<Items>,
+    items_file: &State<ItemsFile>,
+    metrics: &State<AppMetrics>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    if let Some(new_name) = &patch.name {
+        validate_name(new_name, "/items/<id>", metrics)?;
+    }
+    let (items, items_file, metrics) = (items.inner().clone(), items_file.inner().clone(), metrics.inner().clone());
+    on_blocking_thread(move || {
+        let mut map = items.write(&metrics)?;
+        let Some(item) = map.get_mut(&id) else {
+            return Err(ApiError::item_not_found(id));
+        };
+        let patch = patch.into_inner();
+        let changed = patch.name.is_some() || patch.description.is_some() || patch.tags.is_some();
+        if let Some(name) = patch.name {
+            metrics.item_name_length_chars.observe(name.chars().count() as f64);
+            item.name = name;
+        }
+        if let Some(description) = patch.description {
+            item.description = Some(description);
+        }
+        if let Some(tags) = patch.tags {
+            item.tags = tags;
+        }
+        let merged = item_json(id, item, Some("updated"));
+        if changed {
+            save_items(&items_file, &items, &map, &metrics);
+        }
+        Ok(Json(merged))
+    }).await
 }
 
 #[delete("/items/<id>")]
-fn delete_item(id: usize, items: &State<Items>, _timer: Timer) -> Result<Json<serde_json::Value>, NotFound<String>> {
-    let mut items = items.lock().unwrap();
-    if items.remove(&id).is_some() {
-        Ok(Json(json!({
-            "item_id": id,
-            "status": "deleted"
-        })))
-    } else {
-        REQUEST_DATA.with(|data| {
-            if let Some((_, _, status)) = data.borrow_mut().as_mut() {
-                *status = "404".to_string();
+async fn delete_item(
+    id: usize,
+    _open: NotInMaintenance,
+    _rate: WithinRateLimit,
+    items: &State<Items>,
+    items_file: &State<ItemsFile>,
+    metrics: &State<AppMetrics>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let (items, items_file, metrics) = (items.inner().clone(), items_file.inner().clone(), metrics.inner().clone());
+    on_blocking_thread(move || {
+        let mut map = items.write(&metrics)?;
+        if map.remove(&id).is_some() {
+            metrics.items_count.set(map.len() as f64);
+            save_items(&items_file, &items, &map, &metrics);
+            Ok(Json(json!({
+                "item_id": id,
+                "status": "deleted"
+            })))
+        } else {
+            Err(ApiError::item_not_found(id))
+        }
+    }).await
+}
+
+const DEFAULT_SYSTEM_METRICS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Refreshes the system gauges. Runs on a timer (see `spawn_system_metrics`)
+/// rather than per scrape, so scrape latency doesn't depend on syscalls.
+fn update_system_metrics(system: &dyn SystemInfoProvider, metrics: &AppMetrics) {
+    if let Some(usage) = system.cpu_usage() {
+        metrics.process_cpu_usage.set(usage);
+    }
+    if let Some(load) = system.load_avg() {
+        metrics.system_load_average_1m.set(load);
+    }
+    if let Some(mem) = system.mem_info() {
+        metrics.memory_used_bytes.set(mem.total.saturating_sub(mem.free) as f64);
+        metrics.memory_total_bytes.set(mem.total as f64);
+        metrics.memory_free_bytes.set(mem.free as f64);
+        metrics.swap_total_bytes.set(mem.swap_total as f64);
+        metrics.swap_used_bytes.set(mem.swap_total.saturating_sub(mem.swap_free) as f64);
+    }
+    if let Some(threads) = system.thread_count() {
+        metrics.threads_live.set(threads as f64);
+    }
+    if cfg!(target_os = "linux") {
+        if let Some(fds) = system.open_fds() {
+            metrics.process_open_fds.set(fds as f64);
+        }
+        if let Some(max) = system.max_fds() {
+            metrics.process_max_fds.set(max as f64);
+        }
+    }
+    if let Some((total, free)) = system.working_dir_disk() {
+        metrics.disk_total_bytes.set(total as f64);
+        metrics.disk_free_bytes.set(free as f64);
+        metrics.disk_used_bytes.set(total.saturating_sub(free) as f64);
+    }
+}
+
+/// Refreshes the gauges that are cheap enough to read on every scrape: uptime
+/// and the Tokio runtime's worker and task counts.
+fn update_scrape_metrics(metrics: &AppMetrics) {
+    metrics.process_uptime_seconds.set(START_TIME.elapsed().as_secs_f64());
+    if let Ok(runtime) = rocket::tokio::runtime::Handle::try_current() {
+        let runtime = runtime.metrics();
+        metrics.tokio_worker_threads.set(runtime.num_workers() as f64);
+        metrics.tokio_active_tasks.set(runtime.num_alive_tasks() as f64);
+    }
+}
+
+/// Refreshes the system gauges every `interval`, starting immediately. Must be
+/// called from within the Tokio runtime.
+fn spawn_system_metrics(interval: Duration, system: SystemInfo, metrics: AppMetrics) {
+    rocket::tokio::spawn(async move {
+        let mut ticker = rocket::tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let (system, metrics) = (system.clone(), metrics.clone());
+            if let Err(e) = rocket::tokio::task::spawn_blocking(move || update_system_metrics(&*system.0, &metrics)).await {
+                warn!("refreshing system metrics failed: {}", e);
+            }
+        }
+    });
+}
+
+/// Number of one-second samples `http_requests_per_second` averages over.
+const REQUEST_RATE_WINDOW: usize = 10;
+
+/// Samples the request total every second and sets `http_requests_per_second`
+/// to its rate over the last `REQUEST_RATE_WINDOW` seconds, or 0 until there are
+/// two samples. A drop in the total (a metrics reset) starts a new window.
+/// Must be called from within the Tokio runtime.
+fn spawn_request_rate(http: PrometheusFairing) {
+    rocket::tokio::spawn(async move {
+        let mut ticker = rocket::tokio::time::interval(Duration::from_secs(1));
+        let mut samples: VecDeque<(Instant, f64)> = VecDeque::with_capacity(REQUEST_RATE_WINDOW + 1);
+        loop {
+            ticker.tick().await;
+            let now = Instant::now();
+            let total = http.total_requests();
+            if samples.back().is_some_and(|(_, last)| total < *last) {
+                samples.clear();
+            }
+            samples.push_back((now, total));
+            if samples.len() > REQUEST_RATE_WINDOW + 1 {
+                samples.pop_front();
+            }
+            let (since, first) = samples[0];
+            let elapsed = now.duration_since(since).as_secs_f64();
+            http.requests_per_second.set(if elapsed > 0.0 { (total - first) / elapsed } else { 0.0 });
+        }
+    });
+}
+
+/// Sends `GET /` to the server at `address` and checks that
+/// `http_requests_total` moved, which warms up the request path and shows the
+/// fairing is attached and registered.
+async fn self_check(address: SocketAddr, http: PrometheusFairing) -> Result<(), String> {
+    use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let before = http.total_requests();
+    let mut stream = rocket::tokio::net::TcpStream::connect(address).await
+        .map_err(|e| format!("could not connect to {}: {}", address, e))?;
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await
+        .map_err(|e| e.to_string())?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.map_err(|e| e.to_string())?;
+    if !response.starts_with(b"HTTP/1.1 200") {
+        return Err(format!("GET / answered {:?}", String::from_utf8_lossy(&response).lines().next().unwrap_or("")));
+    }
+    if http.total_requests() <= before {
+        return Err("GET / succeeded but http_requests_total did not move (is / in METRICS_EXCLUDE_PATHS?)".to_string());
+    }
+    Ok(())
+}
+
+/// Number of one-second samples `http_route_error_ratio` is computed over.
+const ERROR_RATIO_WINDOW: usize = 60;
+
+/// Samples the per-route request and error totals every second and sets
+/// `http_route_error_ratio` to each route's share of non-2xx responses over the
+/// last `ERROR_RATIO_WINDOW` seconds, 0 for routes without traffic in that time.
+/// A route whose totals dropped (a metrics reset) is measured from zero.
+/// Must be called from within the Tokio runtime.
+fn spawn_error_ratio(http: PrometheusFairing) {
+    rocket::tokio::spawn(async move {
+        let mut ticker = rocket::tokio::time::interval(Duration::from_secs(1));
+        let mut samples: VecDeque<HashMap<String, RouteTotals>> = VecDeque::with_capacity(ERROR_RATIO_WINDOW + 1);
+        loop {
+            ticker.tick().await;
+            let now = http.requests_by_path();
+            samples.push_back(now.clone());
+            if samples.len() > ERROR_RATIO_WINDOW + 1 {
+                samples.pop_front();
+            }
+            for (path, totals) in &now {
+                let then = samples[0].get(path)
+                    .filter(|then| then.requests <= totals.requests)
+                    .copied()
+                    .unwrap_or_default();
+                let requests = totals.requests - then.requests;
+                let ratio = if requests > 0.0 { (totals.errors - then.errors) / requests } else { 0.0 };
+                http.route_error_ratio.with_label_values(&[path]).set(ratio);
             }
-        });
-        Err(NotFound(format!("Item with id {} not found", id)))
+        }
+    });
+}
+
+const DEFAULT_ITEM_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long items are kept, from `ITEM_TTL_SECONDS`, and how often expired ones
+/// are looked for, from `ITEM_SWEEP_INTERVAL_SECONDS`.
+#[derive(Clone, Copy)]
+struct ItemRetention {
+    ttl: Duration,
+    sweep_interval: Duration,
+}
+
+impl ItemRetention {
+    /// `None`, keeping items until they are deleted, when `ITEM_TTL_SECONDS` is
+    /// unset or invalid (the latter logged). An invalid sweep interval is logged
+    /// and replaced by 10 seconds.
+    fn from_env() -> Option<Self> {
+        let value = std::env::var("ITEM_TTL_SECONDS").ok()?;
+        let Some(ttl) = positive_secs(&value) else {
+            warn!("ITEM_TTL_SECONDS={:?} is not a positive number of seconds; items don't expire", value);
+            return None;
+        };
+        let sweep_interval = match std::env::var("ITEM_SWEEP_INTERVAL_SECONDS") {
+            Ok(value) => positive_secs(&value).unwrap_or_else(|| {
+                warn!("ITEM_SWEEP_INTERVAL_SECONDS={:?} is not a positive number of seconds; using 10", value);
+                DEFAULT_ITEM_SWEEP_INTERVAL
+            }),
+            Err(_) => DEFAULT_ITEM_SWEEP_INTERVAL,
+        };
+        Some(ItemRetention { ttl, sweep_interval })
+    }
+}
+
+fn positive_secs(value: &str) -> Option<Duration> {
+    value.parse::<f64>().ok()
+        .filter(|secs| secs.is_finite() && *secs > 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+/// Removes the items created more than `ttl` ago, counting them in
+/// `items_evicted_total`, and persists the store if any were. Items without a
+/// creation time (from an items file written before it was recorded) are kept.
+fn evict_expired_items(ttl: Duration, items: &Items, items_file: &ItemsFile, metrics: &AppMetrics) -> Result<(), ApiError> {
+    let cutoff = persistence::unix_now() - ttl.as_secs_f64();
+    let mut map = items.write(metrics)?;
+    let before = map.len();
+    map.retain(|_, item| item.created_at.is_none_or(|created_at| created_at > cutoff));
+    let evicted = before - map.len();
+    if evicted > 0 {
+        metrics.items_evicted_total.inc_by(evicted as f64);
+        metrics.items_count.set(map.len() as f64);
+        save_items(items_file, items, &map, metrics);
+        info!("evicted {} items older than {:?}", evicted, ttl);
+    }
+    Ok(())
+}
+
+/// Evicts expired items every `sweep_interval` of `retention`. Must be called
+/// from within the Tokio runtime.
+fn spawn_item_eviction(retention: ItemRetention, items: Items, items_file: ItemsFile, metrics: AppMetrics) {
+    rocket::tokio::spawn(async move {
+        let mut ticker = rocket::tokio::time::interval(retention.sweep_interval);
+        loop {
+            ticker.tick().await;
+            let (items, items_file, metrics) = (items.clone(), items_file.clone(), metrics.clone());
+            let sweep = rocket::tokio::task::spawn_blocking(move || evict_expired_items(retention.ttl, &items, &items_file, &metrics));
+            match sweep.await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!("evicting expired items failed: {}", e.message),
+                Err(e) => warn!("evicting expired items failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Writes the same text `/metrics` would serve to `path`, so the final counts
+/// survive a shutdown that happens between scrapes.
+fn dump_metrics(path: &Path, registry: &Registry, system: &dyn SystemInfoProvider, metrics: &AppMetrics) -> prometheus::Result<()> {
+    update_system_metrics(system, metrics);
+    update_scrape_metrics(metrics);
+    let mut file = File::create(path)?;
+    TextEncoder::new().encode(&registry.gather(), &mut file)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Whether `POST /metrics/reset` is enabled, from `ALLOW_METRICS_RESET`. Off by
+/// default so production counters can't be wiped by accident.
+struct AllowMetricsReset(bool);
+
+/// Zeroes the request counters and histograms, for test environments that want
+/// a clean slate between cases without restarting.
+#[post("/metrics/reset")]
+fn reset_metrics(
+    _auth: MetricsAuth,
+    allowed: &State<AllowMetricsReset>,
+    http: &State<PrometheusFairing>,
+    metrics: &State<AppMetrics>,
+) -> Result<Json<serde_json::Value>, Status> {
+    if !allowed.0 {
+        return Err(Status::Forbidden);
+    }
+    http.reset();
+    metrics.http_validation_errors_total.reset();
+    metrics.http_unmatched_requests_total.reset();
+    metrics.http_cache_hits_total.reset();
+    metrics.http_json_errors_total.reset();
+    metrics.http_body_too_large_total.reset();
+    metrics.http_rate_limited_total.reset();
+    Ok(Json(json!({ "status": "reset" })))
+}
+
+/// Switches maintenance mode on or off with `?enabled=`, or flips it without.
+/// Uses the metrics token like the other operator endpoints, but unlike them is
+/// refused with 403 while no token is configured, since it takes the item API
+/// offline.
+#[post("/admin/maintenance?<enabled>")]
+fn set_maintenance(
+    enabled: Option<bool>,
+    _auth: MetricsAuth,
+    token: &State<MetricsToken>,
+    maintenance: &State<Maintenance>,
+    metrics: &State<AppMetrics>,
+) -> Result<Json<serde_json::Value>, Status> {
+    if token.0.is_none() {
+        return Err(Status::Forbidden);
+    }
+    let enabled = enabled.unwrap_or(!maintenance.enabled());
+    maintenance.set(enabled);
+    metrics.maintenance_mode.set(if enabled { 1.0 } else { 0.0 });
+    if enabled {
+        warn!("maintenance mode on: item routes answer 503");
+    } else {
+        info!("maintenance mode off");
+    }
+    Ok(Json(json!({ "maintenance": enabled })))
+}
+
+/// A one-off diagnostic read from the same sources as the matching gauges.
+/// Only mounted with `DEBUG_ENDPOINTS`.
+#[get("/debug/stats")]
+fn debug_stats(
+    _auth: MetricsAuth,
+    items: &State<Items>,
+    system: &State<SystemInfo>,
+    http: &State<PrometheusFairing>,
+    metrics: &State<AppMetrics>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let map = items.read(metrics)?;
+    let (count, bytes) = (map.len(), store_bytes(&map));
+    drop(map);
+    Ok(Json(json!({
+        "items": count,
+        "items_store_bytes": bytes,
+        "threads": system.0.thread_count(),
+        "requests_in_progress": http.requests_in_progress.get() as i64,
+    })))
+}
+
+/// Request totals as JSON. With `?reset=true` they are zeroed as they are read,
+/// so a sidecar polling this gets disjoint deltas; like `/metrics/reset`, that
+/// is refused with 403 unless `ALLOW_METRICS_RESET` is set.
+#[get("/metrics/snapshot?<reset>")]
+fn metrics_snapshot(
+    reset: Option<bool>,
+    _auth: MetricsAuth,
+    allowed: &State<AllowMetricsReset>,
+    http: &State<PrometheusFairing>,
+) -> Result<Json<serde_json::Value>, Status> {
+    let reset = reset.unwrap_or(false);
+    if reset && !allowed.0 {
+        return Err(Status::Forbidden);
+    }
+    Ok(Json(json!({ "requests": http.snapshot(reset), "reset": reset })))
+}
+
+/// Serves every registered metric, or with `?prefix=` only the families whose
+/// name starts with it.
+#[get("/metrics?<prefix>")]
+fn metrics(
+    prefix: Option<&str>,
+    _auth: MetricsAuth,
+    format: MetricsFormat,
+    gzip: AcceptsGzip,
+    registry: &State<Registry>,
+    http: &State<PrometheusFairing>,
+    metrics: &State<AppMetrics>,
+) -> MetricsBody {
+    let timer = metrics.metrics_scrape_duration_seconds.start_timer();
+    update_scrape_metrics(metrics);
+    let mut families = gather(registry, http);
+    if let Some(prefix) = prefix {
+        families.retain(|family| family.get_name().starts_with(prefix));
     }
+    MetricsBody::new(families, format, &http.exemplars, gzip.0).timed(timer)
 }
 
-#[get("/metrics")]
-fn metrics(_timer: Timer) -> String {
-    // Update system metrics
-    if let Ok(load) = loadavg() {
-        PROCESS_CPU_USAGE.set(load.one);
+/// Serves the single family called `name`, or 404 when nothing by that name
+/// is registered.
+#[get("/metrics/<name>")]
+fn metric_family(
+    name: &str,
+    _auth: MetricsAuth,
+    format: MetricsFormat,
+    gzip: AcceptsGzip,
+    registry: &State<Registry>,
+    http: &State<PrometheusFairing>,
+    metrics: &State<AppMetrics>,
+) -> Result<MetricsBody, Status> {
+    update_scrape_metrics(metrics);
+    let mut families = gather(registry, http);
+    families.retain(|family| family.get_name() == name);
+    if families.is_empty() {
+        return Err(Status::NotFound);
     }
-    if let Ok(mem) = mem_info() {
-        MEMORY_USED_BYTES.set((mem.total - mem.free) as f64);
+    Ok(MetricsBody::new(families, format, &http.exemplars, gzip.0))
+}
+
+/// The same families as `/metrics`, structured as JSON for consumers that
+/// can't parse the exposition formats.
+#[get("/metrics.json")]
+fn metrics_json(
+    _auth: MetricsAuth,
+    registry: &State<Registry>,
+    http: &State<PrometheusFairing>,
+    metrics: &State<AppMetrics>,
+) -> Json<Vec<FamilyJson>> {
+    update_scrape_metrics(metrics);
+    Json(gather(registry, http).iter().map(FamilyJson::from_family).collect())
+}
+
+/// Name, type, help and label names of every family, for tooling that builds
+/// dashboards. Like the other endpoints it lists what the registry gathers, so a
+/// labelled family shows up once it has its first series.
+#[get("/metrics/catalog")]
+fn metrics_catalog(_auth: MetricsAuth, registry: &State<Registry>, http: &State<PrometheusFairing>) -> Json<Vec<CatalogEntry>> {
+    Json(gather(registry, http).iter().map(CatalogEntry::from_family).collect())
+}
+
+/// The registry's families. `Registry::gather` already orders families by name
+/// and series by labels; `STABLE_METRICS_ORDER` sorts them once more here so
+/// golden files don't depend on that implementation detail.
+fn gather(registry: &Registry, http: &PrometheusFairing) -> Vec<MetricFamily> {
+    let mut families = registry.gather();
+    if http.stable_metrics_order {
+        exposition::sort_families(&mut families);
+    }
+    families
+}
+
+/// Error catchers; `default` covers the remaining statuses (e.g. 400 for a
+/// malformed body) so every error is an `ApiError`. Requests that matched no
+/// route are also recorded in `http_requests_total` with `path="unmatched"` by
+/// `PrometheusFairing`; this adds a dedicated counter that is easy to alert on.
+/// Rocket's `Json` guard fails with 400 for bodies that aren't JSON and with
+/// 422 for JSON of the wrong shape, before any handler runs.
+#[catch(400)]
+fn bad_request(status: Status, request: &Request<'_>) -> ApiError {
+    json_error_catcher(status, request)
+}
+
+#[catch(422)]
+fn unprocessable_entity(status: Status, request: &Request<'_>) -> ApiError {
+    json_error_catcher(status, request)
+}
+
+/// The body was cut off at Rocket's `json` limit (`MAX_BODY_BYTES`); counted in
+/// `http_body_too_large_total` against the route it was sent to.
+#[catch(413)]
+fn payload_too_large(status: Status, request: &Request<'_>) -> ApiError {
+    let metrics = request.rocket().state::<AppMetrics>();
+    if let (Some(route), Some(metrics)) = (request.route(), metrics) {
+        metrics.http_body_too_large_total.with_label_values(&[route.uri.path()]).inc();
+    }
+    let limit = request.limits().get("json").unwrap_or(Limits::JSON);
+    ApiError::new(status, format!("request body exceeds the limit of {} bytes", limit.as_u64()))
+}
+
+/// Counts the rejected body in `http_json_errors_total` against the route it
+/// was sent to, and explains the rejection when `CheckedJson` recorded why.
+/// Bodies rejected for an unknown field were counted as validation errors
+/// already and name the field instead.
+fn json_error_catcher(status: Status, request: &Request<'_>) -> ApiError {
+    let rejection = request.local_cache(JsonRejection::default);
+    let metrics = request.rocket().state::<AppMetrics>();
+    if let (Some(route), Some(metrics), None) = (request.route(), metrics, &rejection.unknown_field) {
+        metrics.http_json_errors_total.with_label_values(&[route.uri.path()]).inc();
     }
-    THREADS_LIVE.set(num_cpus::get() as f64);
+    let error = match &rejection.message {
+        Some(message) => ApiError::new(status, message.clone()),
+        None => return error_catcher(status, request),
+    };
+    match &rejection.unknown_field {
+        Some(field) => error.with_field(field.clone()),
+        None => error,
+    }
+}
+
+#[catch(404)]
+fn not_found(status: Status, request: &Request<'_>) -> ApiError {
+    error_catcher(status, request)
+}
+
+#[catch(429)]
+fn too_many_requests(status: Status, request: &Request<'_>) -> ApiError {
+    error_catcher(status, request)
+}
+
+#[catch(500)]
+fn internal_error(status: Status, request: &Request<'_>) -> ApiError {
+    error_catcher(status, request)
+}
 
-    let encoder = TextEncoder::new();
-    let mut buffer = Vec::new();
-    encoder.encode(&REGISTRY.gather(), &mut buffer).unwrap();
-    String::from_utf8(buffer).unwrap()
+#[catch(default)]
+fn default_error(status: Status, request: &Request<'_>) -> ApiError {
+    error_catcher(status, request)
+}
+
+fn error_catcher(status: Status, request: &Request<'_>) -> ApiError {
+    let metrics = request.rocket().state::<AppMetrics>();
+    if let (None, Some(metrics)) = (request.route(), metrics) {
+        metrics.http_unmatched_requests_total
+            .with_label_values(&[fairing::request_method(request).as_str(), &status.code.to_string()])
+            .inc();
+    }
+    ApiError::new(status, status.reason().unwrap_or("Unknown Error"))
+}
+
+/// Installs the subscriber for the request spans emitted by `PrometheusFairing`,
+/// filtered by `RUST_LOG` (default `info`). It doesn't capture the `log` records
+/// Rocket writes through its own logger.
+fn init_tracing() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter).finish();
+    if let Err(e) = tracing::subscriber::set_global_default(subscriber) {
+        eprintln!("Failed to install tracing subscriber: {}", e);
+    }
 }
 
 #[launch]
 fn rocket() -> _ {
-    REGISTRY.register(Box::new(HTTP_REQUESTS_TOTAL.clone())).unwrap();
-    REGISTRY.register(Box::new(HTTP_REQUESTS_DURATION.clone())).unwrap();
-    REGISTRY.register(Box::new(HTTP_REQUESTS_IN_PROGRESS.clone())).unwrap();
-    REGISTRY.register(Box::new(PROCESS_CPU_USAGE.clone())).unwrap();
-    REGISTRY.register(Box::new(MEMORY_USED_BYTES.clone())).unwrap();
-    REGISTRY.register(Box::new(THREADS_LIVE.clone())).unwrap();
-
-    rocket::build()
-        .manage(Mutex::new(HashMap::<usize, String>::new()))
-        .mount("/", routes![index, create_item, read_item, update_item, delete_item, metrics])
+    init_tracing();
+    build(SystemInfo(Arc::new(HostSystem::default())))
+}
+
+/// The app with its system gauges read from `system`, configured from the
+/// environment. Separate from `rocket()` so tests can assemble it around a
+/// fake provider without installing the global tracing subscriber.
+fn build(system: SystemInfo) -> Rocket<Build> {
+    lazy_static::initialize(&START_TIME);
+
+    let rocket = rocket::build();
+    let figment = config::limits_figment(config::bind_figment(rocket.figment().clone()));
+    let figment = config::tls_figment(figment).unwrap_or_else(|e| {
+        error!("{}", e);
+        std::process::exit(1);
+    });
+    let rocket = rocket.configure(figment.clone());
+    let config = MetricsConfig::from_env();
+    let mut metrics = registry::build_registry(&config).unwrap_or_else(|e| {
+        error!("could not set up metrics: {}", e);
+        std::process::exit(1);
+    });
+    if config.access_log == AccessLog::Json {
+        metrics.http.access_log_writer = AccessLogWriter::from_env();
+    }
+    let items_file = ItemsFile::from_env();
+    let stored_items = items_file.load();
+    metrics.app.items_count.set(stored_items.items.len() as f64);
+    metrics.app.items_store_bytes.set(store_bytes(&stored_items.items) as f64);
+    metrics.app.service_ready.set(1.0);
+    let maintenance = Maintenance::from_env();
+    metrics.app.maintenance_mode.set(if maintenance.enabled() { 1.0 } else { 0.0 });
+    let items = Items::new(stored_items);
+    let oldest_item_age = OldestItemAge::new(items.clone(), &config.namespace)
+        .and_then(|collector| registry::register(&metrics.registry, &collector));
+    if let Err(e) = oldest_item_age {
+        error!("could not set up metrics: {}", e);
+        std::process::exit(1);
+    }
+    let catchers = catchers![bad_request, unprocessable_entity, payload_too_large, not_found, too_many_requests, internal_error, default_error];
+
+    let rocket = rocket
+        .manage(metrics.registry.clone())
+        .manage(metrics.app.clone())
+        .manage(items.clone())
+        .manage(items_file)
+        .manage(MetricsToken::from_env())
+        .manage(AllowMetricsReset(config::env_flag("ALLOW_METRICS_RESET")))
+        .manage(RateLimiter::from_env())
+        .manage(IdempotencyKeys::from_env())
+        .manage(maintenance.clone())
+        .manage(DefaultItemName::from_env())
+        .manage(system.clone())
+        .register("/", catchers.clone());
+    let mut routes = routes![index, list_items, count_items, search_items, create_item, create_item_autonamed, create_items_bulk, read_item, update_item, patch_item, delete_item];
+    let mut admin_routes = routes![healthz, readyz, metrics, metric_family, metrics_json, metrics_catalog, metrics_snapshot, reset_metrics, set_maintenance];
+    if config::env_flag("DEBUG_ENDPOINTS") {
+        admin_routes.extend(routes![debug_stats]);
+    }
+    // With ADMIN_PORT the admin endpoints move to a second Rocket instance
+    // sharing the registry and state, so network policy can tell them apart
+    let admin = match config::admin_port() {
+        Some(port) => Some(rocket::custom(figment.merge(("port", port)))
+            .manage(metrics.registry.clone())
+            .manage(metrics.app.clone())
+            .manage(items)
+            .manage(MetricsToken::from_env())
+            .manage(AllowMetricsReset(config::env_flag("ALLOW_METRICS_RESET")))
+            .manage(maintenance)
+            .manage(system)
+            .register("/", catchers)
+            .mount("/", admin_routes)),
+        None => {
+            routes.extend(admin_routes);
+            None
+        }
+    };
+    let routes = match std::env::var("REQUEST_TIMEOUT_MS") {
+        Ok(value) => match value.parse::<u64>() {
+            Ok(ms) if ms > 0 => timeout::with_timeout(routes, Duration::from_millis(ms), &metrics.http.request_timeouts_total),
+            _ => {
+                warn!("REQUEST_TIMEOUT_MS={:?} is not a positive number of milliseconds; not enforcing a timeout", value);
+                routes
+            }
+        },
+        Err(_) => routes,
+    };
+    let rocket = rocket.mount("/", routes);
+    let system_interval = match std::env::var("SYSTEM_METRICS_INTERVAL") {
+        Ok(value) => value.parse::<f64>().ok()
+            .filter(|secs| secs.is_finite() && *secs > 0.0)
+            .map(Duration::from_secs_f64)
+            .unwrap_or_else(|| {
+                warn!("SYSTEM_METRICS_INTERVAL={:?} is not a positive number of seconds; using 5", value);
+                DEFAULT_SYSTEM_METRICS_INTERVAL
+            }),
+        Err(_) => DEFAULT_SYSTEM_METRICS_INTERVAL,
+    };
+    let app_metrics = metrics.app.clone();
+    let rocket = rocket.attach(AdHoc::on_liftoff("System metrics", move |rocket| Box::pin(async move {
+        let system = rocket.state::<SystemInfo>().unwrap().clone();
+        spawn_system_metrics(system_interval, system, app_metrics);
+    })));
+    let rocket = match ItemRetention::from_env() {
+        Some(retention) => {
+            let app_metrics = metrics.app.clone();
+            rocket.attach(AdHoc::on_liftoff("Item eviction", move |rocket| Box::pin(async move {
+                let items = rocket.state::<Items>().unwrap().clone();
+                let items_file = rocket.state::<ItemsFile>().unwrap().clone();
+                spawn_item_eviction(retention, items, items_file, app_metrics);
+            })))
+        }
+        None => rocket,
+    };
+    let http_metrics = metrics.http.clone();
+    let rocket = rocket.attach(AdHoc::on_liftoff("Request rate and error ratio", move |_| Box::pin(async move {
+        spawn_request_rate(http_metrics.clone());
+        spawn_error_ratio(http_metrics);
+    })));
+
+    let rocket = if config::env_flag("SELF_CHECK") {
+        let http_metrics = metrics.http.clone();
+        rocket.attach(AdHoc::on_liftoff("Self-check", |rocket| Box::pin(async move {
+            let config = rocket.config();
+            if config.tls_enabled() {
+                warn!("self-check skipped: it only speaks plain HTTP and TLS is enabled");
+                return;
+            }
+            let host = if config.address.is_unspecified() { IpAddr::from([127, 0, 0, 1]) } else { config.address };
+            let address = SocketAddr::new(host, config.port);
+            // Requests are only served once every liftoff callback has returned
+            rocket::tokio::spawn(async move {
+                match self_check(address, http_metrics).await {
+                    Ok(()) => info!("self-check passed: request metrics are being recorded"),
+                    Err(e) => warn!("self-check failed: {}", e),
+                }
+            });
+        })))
+    } else {
+        rocket
+    };
+
+    let (rocket, admin) = match std::env::var("CORS_ALLOW_ORIGINS") {
+        Ok(origins) => (
+            CorsFairing::new(&origins).attach(rocket),
+            admin.map(|admin| CorsFairing::new(&origins).attach(admin)),
+        ),
+        Err(_) => (rocket, admin),
+    };
+
+    let rocket = match admin {
+        Some(admin) => {
+            let admin = metrics.http.clone().attach(admin);
+            rocket.attach(AdHoc::on_liftoff("Admin server", |rocket| Box::pin(async move {
+                let shutdown = rocket.shutdown();
+                rocket::tokio::spawn(async move {
+                    let admin = match admin.ignite().await {
+                        Ok(admin) => admin,
+                        Err(e) => {
+                            error!("could not start the admin server: {}", e);
+                            std::process::exit(1);
+                        }
+                    };
+                    let admin_shutdown = admin.shutdown();
+                    rocket::tokio::spawn(async move {
+                        shutdown.await;
+                        admin_shutdown.notify();
+                    });
+                    if let Err(e) = admin.launch().await {
+                        error!("admin server failed: {}", e);
+                        std::process::exit(1);
+                    }
+                });
+            })))
+        }
+        None => rocket,
+    };
+
+    let registry = metrics.registry.clone();
+    let rocket = match std::env::var("PUSHGATEWAY_URL") {
+        Ok(url) => rocket.attach(AdHoc::on_liftoff("Pushgateway", |_| Box::pin(async move {
+            let job = std::env::var("PUSHGATEWAY_JOB").unwrap_or_else(|_| env!("CARGO_PKG_NAME").to_string());
+            let interval = std::env::var("PUSHGATEWAY_INTERVAL_SECS").ok()
+                .and_then(|secs| secs.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(push::DEFAULT_PUSH_INTERVAL);
+            push::spawn_pushgateway(url, job, interval, registry);
+        }))),
+        Err(_) => rocket,
+    };
+
+    let rocket = match std::env::var("STATSD_ADDR") {
+        Ok(addr) => match StatsdFairing::new(&addr, &config.namespace) {
+            Ok(statsd) => rocket.attach(statsd),
+            Err(e) => {
+                warn!("STATSD_ADDR={:?} is unusable, not exporting to StatsD: {}", addr, e);
+                rocket
+            }
+        },
+        Err(_) => rocket,
+    };
+
+    let (registry, app_metrics) = (metrics.registry.clone(), metrics.app.clone());
+    let rocket = match std::env::var_os("METRICS_SHUTDOWN_DUMP") {
+        Some(path) => rocket.attach(AdHoc::on_shutdown("Metrics shutdown dump", |rocket| Box::pin(async move {
+            let path = PathBuf::from(path);
+            let system = rocket.state::<SystemInfo>().unwrap().clone();
+            let dump_path = path.clone();
+            let dump = rocket::tokio::task::spawn_blocking(move || {
+                dump_metrics(&dump_path, &registry, &*system.0, &app_metrics).map_err(|e| e.to_string())
+            });
+            match dump.await.map_err(|e| e.to_string()).and_then(|dumped| dumped) {
+                Ok(()) => info!("wrote final metrics snapshot to {}", path.display()),
+                Err(e) => error!("could not write final metrics snapshot to {}: {}", path.display(), e),
+            }
+        }))),
+        None => rocket,
+    };
+
+    let rocket = match std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(_) => {
+            let service_name = std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| env!("CARGO_PKG_NAME").to_string());
+            match OtelFairing::new(service_name, &config) {
+                Ok(otel) => rocket.attach(otel),
+                Err(e) => {
+                    warn!("could not set up the OTLP metrics exporter: {}", e);
+                    rocket
+                }
+            }
+        }
+        Err(_) => rocket,
+    };
+    metrics.http.attach(rocket)
 }
\ No newline at end of file
@@ -0,0 +1,40 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+
+/// Whether the items API is shut for maintenance, starting from `MAINTENANCE`
+/// and switched with `POST /admin/maintenance`. Clones share the mode.
+#[derive(Clone)]
+pub struct Maintenance(Arc<AtomicBool>);
+
+impl Maintenance {
+    pub fn from_env() -> Self {
+        Maintenance(Arc::new(AtomicBool::new(crate::config::env_flag("MAINTENANCE"))))
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, enabled: bool) {
+        self.0.store(enabled, Ordering::Relaxed);
+    }
+}
+
+/// Request guard for the item routes: fails with 503 while in maintenance mode.
+/// Health checks and metrics don't use it, so they keep answering.
+pub struct NotInMaintenance;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for NotInMaintenance {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.rocket().state::<Maintenance>() {
+            Some(maintenance) if maintenance.enabled() => Outcome::Error((Status::ServiceUnavailable, ())),
+            _ => Outcome::Success(NotInMaintenance),
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use std::io::Write;
+
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+use prometheus::Encoder;
+
+use crate::exemplars::{Exemplar, Exemplars};
+
+pub const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// The line every OpenMetrics exposition ends with.
+pub const EOF: &str = "# EOF\n";
+
+/// Units OpenMetrics recognises from a metric name suffix, reported in `# UNIT`.
+const UNITS: [&str; 2] = ["seconds", "bytes"];
+
+/// Encodes metric families in the OpenMetrics 1.0 text format, which the
+/// `prometheus` crate doesn't provide. Differences from `TextEncoder`: counter
+/// families are named without `_total`, `# UNIT` lines are emitted for
+/// `_seconds`/`_bytes` metrics, histogram buckets carry any recorded exemplars
+/// and the exposition ends with `# EOF`.
+#[derive(Debug, Default)]
+pub struct OpenMetricsEncoder {
+    exemplars: Option<Exemplars>,
+}
+
+impl OpenMetricsEncoder {
+    pub fn with_exemplars(exemplars: Exemplars) -> Self {
+        OpenMetricsEncoder { exemplars: Some(exemplars) }
+    }
+
+    /// Encodes `families` without the closing `# EOF`, so an exposition can be
+    /// written a few families at a time and ended with `EOF`.
+    pub fn encode_families<W: Write>(&self, families: &[MetricFamily], writer: &mut W) -> prometheus::Result<()> {
+        for family in families {
+            let name = family.get_name();
+            let field_type = family.get_field_type();
+            let family_name = match field_type {
+                MetricType::COUNTER => name.strip_suffix("_total").unwrap_or(name),
+                _ => name,
+            };
+            let type_name = match field_type {
+                MetricType::COUNTER => "counter",
+                MetricType::GAUGE => "gauge",
+                MetricType::HISTOGRAM => "histogram",
+                MetricType::SUMMARY => "summary",
+                MetricType::UNTYPED => "unknown",
+            };
+
+            writeln!(writer, "# TYPE {} {}", family_name, type_name)?;
+            if let Some(unit) = UNITS.iter().find(|unit| family_name.ends_with(&format!("_{}", unit))) {
+                writeln!(writer, "# UNIT {} {}", family_name, unit)?;
+            }
+            if !family.get_help().is_empty() {
+                writeln!(writer, "# HELP {} {}", family_name, escape(family.get_help()))?;
+            }
+
+            for metric in family.get_metric() {
+                match field_type {
+                    MetricType::COUNTER => {
+                        write_sample(writer, family_name, "_total", metric, None, metric.get_counter().get_value())?;
+                    }
+                    MetricType::GAUGE => {
+                        write_sample(writer, family_name, "", metric, None, metric.get_gauge().get_value())?;
+                    }
+                    MetricType::UNTYPED => {
+                        write_sample(writer, family_name, "", metric, None, metric.get_untyped().get_value())?;
+                    }
+                    MetricType::HISTOGRAM => {
+                        let histogram = metric.get_histogram();
+                        let mut inf_seen = false;
+                        for bucket in histogram.get_bucket() {
+                            let bound = bucket.get_upper_bound();
+                            inf_seen |= bound == f64::INFINITY;
+                            let le = format_float(bound);
+                            write_bucket(writer, family_name, metric, &le, bucket.get_cumulative_count(), self.exemplar(name, metric, bound))?;
+                        }
+                        if !inf_seen {
+                            let exemplar = self.exemplar(name, metric, f64::INFINITY);
+                            write_bucket(writer, family_name, metric, "+Inf", histogram.get_sample_count(), exemplar)?;
+                        }
+                        write_sample(writer, family_name, "_sum", metric, None, histogram.get_sample_sum())?;
+                        write_sample(writer, family_name, "_count", metric, None, histogram.get_sample_count() as f64)?;
+                    }
+                    MetricType::SUMMARY => {
+                        let summary = metric.get_summary();
+                        for quantile in summary.get_quantile() {
+                            let q = format_float(quantile.get_quantile());
+                            write_sample(writer, family_name, "", metric, Some(("quantile", &q)), quantile.get_value())?;
+                        }
+                        write_sample(writer, family_name, "_sum", metric, None, summary.get_sample_sum())?;
+                        write_sample(writer, family_name, "_count", metric, None, summary.get_sample_count() as f64)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn exemplar(&self, family: &str, metric: &Metric, bound: f64) -> Option<Exemplar> {
+        self.exemplars.as_ref()?.get(family, metric.get_label(), bound)
+    }
+}
+
+impl Encoder for OpenMetricsEncoder {
+    fn encode<W: Write>(&self, families: &[MetricFamily], writer: &mut W) -> prometheus::Result<()> {
+        self.encode_families(families, writer)?;
+        writer.write_all(EOF.as_bytes())?;
+        Ok(())
+    }
+
+    fn format_type(&self) -> &str {
+        OPENMETRICS_FORMAT
+    }
+}
+
+/// Writes a `_bucket` sample followed by its exemplar, if any, as
+/// `... # {trace_id="..."} <value> <timestamp>`.
+fn write_bucket<W: Write>(
+    writer: &mut W,
+    name: &str,
+    metric: &Metric,
+    le: &str,
+    count: u64,
+    exemplar: Option<Exemplar>,
+) -> prometheus::Result<()> {
+    write!(writer, "{}_bucket", name)?;
+    write_labels(writer, metric.get_label(), Some(("le", le)))?;
+    write!(writer, " {}", count)?;
+    if let Some(exemplar) = exemplar {
+        let labels: Vec<String> = exemplar.labels.iter()
+            .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+            .collect();
+        write!(writer, " # {{{}}} {} {}", labels.join(","), format_float(exemplar.value), exemplar.timestamp)?;
+    }
+    writeln!(writer)?;
+    Ok(())
+}
+
+fn write_sample<W: Write>(
+    writer: &mut W,
+    name: &str,
+    suffix: &str,
+    metric: &Metric,
+    extra_label: Option<(&str, &str)>,
+    value: f64,
+) -> prometheus::Result<()> {
+    write!(writer, "{}{}", name, suffix)?;
+    write_labels(writer, metric.get_label(), extra_label)?;
+    write!(writer, " {}", format_float(value))?;
+    let timestamp = metric.get_timestamp_ms();
+    if timestamp != 0 {
+        // OpenMetrics timestamps are in seconds.
+        write!(writer, " {}", timestamp as f64 / 1000.0)?;
+    }
+    writeln!(writer)?;
+    Ok(())
+}
+
+fn write_labels<W: Write>(
+    writer: &mut W,
+    labels: &[LabelPair],
+    extra_label: Option<(&str, &str)>,
+) -> prometheus::Result<()> {
+    let pairs = labels.iter()
+        .map(|pair| (pair.get_name(), pair.get_value()))
+        .chain(extra_label);
+    let mut separator = "{";
+    for (name, value) in pairs {
+        write!(writer, "{}{}=\"{}\"", separator, name, escape(value))?;
+        separator = ",";
+    }
+    if separator == "," {
+        write!(writer, "}}")?;
+    }
+    Ok(())
+}
+
+/// Escapes `\`, `"` and newlines, as OpenMetrics requires for both label values and help text.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn format_float(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else if value.is_nan() {
+        "NaN".to_string()
+    } else {
+        value.to_string()
+    }
+}
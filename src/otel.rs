@@ -0,0 +1,127 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use opentelemetry::metrics::{Counter, Histogram, MeterProvider, UpDownCounter};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{ExporterBuildError, MetricExporter};
+use opentelemetry_sdk::metrics::exporter::PushMetricExporter;
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::Resource;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Orbit, Request, Response, Rocket};
+
+use crate::config::MetricsConfig;
+use crate::fairing::{is_instrumented, normalized_path, request_method};
+
+struct OtelStart(Instant);
+
+/// The request's contribution to `http_requests_in_progress`, taken back by
+/// `on_response` or, if Rocket drops the request unanswered, on drop.
+struct OtelInProgress {
+    gauge: UpDownCounter<i64>,
+    released: AtomicBool,
+}
+
+impl OtelInProgress {
+    fn release(&self) {
+        if !self.released.swap(true, Ordering::AcqRel) {
+            self.gauge.add(-1, &[]);
+        }
+    }
+}
+
+impl Drop for OtelInProgress {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// Mirrors the core HTTP metrics (request count, duration histogram and
+/// in-progress requests) into OpenTelemetry instruments exported over OTLP/HTTP.
+/// The exporter reads `OTEL_EXPORTER_OTLP_ENDPOINT` and the other standard
+/// `OTEL_EXPORTER_OTLP_*` variables itself.
+pub struct OtelFairing {
+    provider: SdkMeterProvider,
+    requests_total: Counter<u64>,
+    requests_duration: Histogram<f64>,
+    requests_in_progress: UpDownCounter<i64>,
+}
+
+impl OtelFairing {
+    /// Builds the exporter and instruments, using `service_name` for the
+    /// resource and the histogram buckets from `config`.
+    pub fn new(service_name: String, config: &MetricsConfig) -> Result<Self, ExporterBuildError> {
+        let exporter = MetricExporter::builder().with_http().build()?;
+        Ok(OtelFairing::with_exporter(exporter, service_name, config))
+    }
+
+    /// Like `new`, but exports to `exporter` instead of the OTLP endpoint.
+    pub fn with_exporter(exporter: impl PushMetricExporter, service_name: String, config: &MetricsConfig) -> Self {
+        let provider = SdkMeterProvider::builder()
+            .with_periodic_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name(service_name).build())
+            .build();
+        let meter = provider.meter(env!("CARGO_PKG_NAME"));
+        OtelFairing {
+            requests_total: meter.u64_counter("http_requests_total")
+                .with_description("Total HTTP Requests")
+                .build(),
+            requests_duration: meter.f64_histogram("http_request_duration_seconds")
+                .with_description("HTTP Request Duration")
+                .with_unit("s")
+                .with_boundaries(config.duration_buckets.clone())
+                .build(),
+            requests_in_progress: meter.i64_up_down_counter("http_requests_in_progress")
+                .with_description("Number of HTTP requests in progress")
+                .build(),
+            provider,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for OtelFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "OpenTelemetry HTTP metrics",
+            kind: Kind::Request | Kind::Response | Kind::Shutdown,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        if !is_instrumented(request) {
+            return;
+        }
+        request.local_cache(|| OtelStart(Instant::now()));
+        request_method(request);
+        self.requests_in_progress.add(1, &[]);
+        let gauge = self.requests_in_progress.clone();
+        request.local_cache(|| Some(OtelInProgress { gauge, released: AtomicBool::new(false) }));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if !is_instrumented(request) {
+            return;
+        }
+        let duration = request.local_cache(|| OtelStart(Instant::now())).0.elapsed().as_secs_f64();
+        let attributes = [
+            KeyValue::new("method", request_method(request).as_str()),
+            KeyValue::new("status", i64::from(response.status().code)),
+            KeyValue::new("path", normalized_path(request).to_string()),
+        ];
+        self.requests_total.add(1, &attributes);
+        self.requests_duration.record(duration, &attributes);
+        if let Some(in_progress) = request.local_cache(|| None::<OtelInProgress>) {
+            in_progress.release();
+        }
+    }
+
+    /// Flushes what the periodic reader hasn't exported yet.
+    async fn on_shutdown(&self, _: &Rocket<Orbit>) {
+        let provider = self.provider.clone();
+        let result = rocket::tokio::task::spawn_blocking(move || provider.shutdown()).await;
+        if let Ok(Err(e)) = result {
+            warn!("OpenTelemetry metrics shutdown failed: {}", e);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rocket::serde::{Deserialize, Deserializer, Serialize};
+
+/// Where the item store is persisted, from `ITEMS_FILE`. `None` keeps items in
+/// memory only. The in-memory map stays the source of truth; the file is just
+/// rewritten after every mutation and read back at launch.
+#[derive(Clone)]
+pub struct ItemsFile(pub Option<PathBuf>);
+
+/// A stored item, and the body of `POST /items` and `PUT /items/<id>`. Fields
+/// other than `name` may be left out and default to empty; unknown fields are
+/// rejected rather than dropped. The items file is read through `FileItem`,
+/// which doesn't reject them.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Item {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Creation time in seconds since the Unix epoch. Set by the server and
+    /// never read from a request body; `None` for items stored before it was
+    /// tracked.
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<f64>,
+}
+
+impl Item {
+    fn named(name: String) -> Self {
+        Item { name, description: None, tags: Vec::new(), created_at: None }
+    }
+}
+
+/// Seconds since the Unix epoch, the unit of `Item::created_at`.
+pub fn unix_now() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |since| since.as_secs_f64())
+}
+
+/// An item object of the items file, where `created_at` is read back too.
+/// Fields this version doesn't know, say from a newer one, are ignored, so
+/// they don't make the whole file unreadable.
+#[derive(Deserialize)]
+struct FileItem {
+    name: String,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    created_at: Option<f64>,
+}
+
+/// An entry of the items file: an object since items have more than a name,
+/// the name alone before that.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredItem {
+    Item(FileItem),
+    Name(String),
+}
+
+fn items_or_names<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<usize, Item>, D::Error> {
+    let stored = HashMap::<usize, StoredItem>::deserialize(deserializer)?;
+    Ok(stored.into_iter()
+        .map(|(id, item)| match item {
+            StoredItem::Item(FileItem { name, description, tags, created_at }) => (id, Item { name, description, tags, created_at }),
+            StoredItem::Name(name) => (id, Item::named(name)),
+        })
+        .collect())
+}
+
+/// Contents of the items file. `next_id` is stored alongside the items so ids
+/// of deleted items aren't handed out again after a restart.
+#[derive(Default, Deserialize)]
+pub struct StoredItems {
+    pub next_id: usize,
+    #[serde(deserialize_with = "items_or_names")]
+    pub items: HashMap<usize, Item>,
+}
+
+impl StoredItems {
+    /// Files written before `next_id` was stored hold only the id → name map.
+    fn from_map(items: HashMap<usize, String>) -> Self {
+        let next_id = items.keys().max().map_or(1, |id| id + 1);
+        let items = items.into_iter().map(|(id, name)| (id, Item::named(name))).collect();
+        StoredItems { next_id, items }
+    }
+}
+
+#[derive(Serialize)]
+struct StoredItemsRef<'a> {
+    next_id: usize,
+    items: BTreeMap<&'a usize, &'a Item>,
+}
+
+impl ItemsFile {
+    pub fn from_env() -> Self {
+        ItemsFile(std::env::var_os("ITEMS_FILE").filter(|path| !path.is_empty()).map(PathBuf::from))
+    }
+
+    /// Reads the persisted items. A missing file yields an empty store, and so
+    /// does an unreadable one rather than failing launch; it is logged and
+    /// moved aside first, so the next save doesn't overwrite it.
+    pub fn load(&self) -> StoredItems {
+        let Some(path) = &self.0 else {
+            return StoredItems::default();
+        };
+        match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .or_else(|e| serde_json::from_slice(&bytes).map(StoredItems::from_map).map_err(|_| e))
+                .unwrap_or_else(|e| {
+                    error!("ignoring corrupt items file {}: {}", path.display(), e);
+                    set_aside(path);
+                    StoredItems::default()
+                }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => StoredItems::default(),
+            Err(e) => {
+                error!("could not read items file {}: {}", path.display(), e);
+                set_aside(path);
+                StoredItems::default()
+            }
+        }
+    }
+
+    /// Writes `items` to a temporary file next to the target and renames it into
+    /// place, so a crash mid-write never leaves a truncated file behind. The
+    /// temporary file is synced before the rename and the directory after it,
+    /// so a power loss can't leave the rename without the contents, or undo it.
+    pub fn save(&self, items: &HashMap<usize, Item>, next_id: usize) -> io::Result<()> {
+        let Some(path) = &self.0 else {
+            return Ok(());
+        };
+        let stored = StoredItemsRef { next_id, items: items.iter().collect() };
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec_pretty(&stored)?)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&tmp, path)?;
+        sync_parent(path)
+    }
+}
+
+/// Renames an items file that couldn't be loaded to
+/// `<path>.unreadable-<unix seconds>`, keeping it for recovery by hand.
+fn set_aside(path: &Path) {
+    let mut aside = path.as_os_str().to_owned();
+    aside.push(format!(".unreadable-{}", unix_now() as u64));
+    match fs::rename(path, &aside) {
+        Ok(()) => warn!("moved the unreadable items file to {}", Path::new(&aside).display()),
+        Err(e) => error!("could not move the unreadable items file {} aside, the next save replaces it: {}", path.display(), e),
+    }
+}
+
+/// Syncs the directory holding `path`, making a rename into it durable.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    File::open(parent)?.sync_all()
+}
+
+/// Directories can't be opened for syncing here; the rename is left to the OS.
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
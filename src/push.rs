@@ -0,0 +1,30 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use prometheus::Registry;
+use rocket::tokio;
+
+pub const DEFAULT_PUSH_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Periodically pushes everything in `registry` to the Pushgateway at `url`
+/// under `job`, for runs that may exit before Prometheus scrapes them. A failed
+/// push is logged and retried on the next tick. Must be called from within the
+/// Tokio runtime.
+pub fn spawn_pushgateway(url: String, job: String, interval: Duration, registry: Registry) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let (url, job, registry) = (url.clone(), job.clone(), registry.clone());
+            // `prometheus::push` uses a blocking HTTP client.
+            let pushed = tokio::task::spawn_blocking(move || {
+                prometheus::push_add_metrics(&job, HashMap::new(), &url, registry.gather(), None)
+            }).await;
+            match pushed {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!("pushing metrics to the Pushgateway failed: {}", e),
+                Err(e) => warn!("Pushgateway task failed: {}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+
+use crate::fairing::normalized_path;
+use crate::registry::AppMetrics;
+
+/// Beyond this many tracked clients, buckets that have refilled completely are
+/// dropped, since a full bucket is the same as no bucket.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Per-client-IP token buckets holding up to `per_sec` tokens and refilling at
+/// `per_sec` tokens a second, from `RATE_LIMIT_PER_SEC`. `None` disables limiting.
+pub struct RateLimiter {
+    per_sec: Option<f64>,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_sec: Option<f64>) -> Self {
+        RateLimiter { per_sec, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    /// Reads `RATE_LIMIT_PER_SEC`; unset, non-positive or unparsable values
+    /// (the latter logged) disable limiting.
+    pub fn from_env() -> Self {
+        let per_sec = std::env::var("RATE_LIMIT_PER_SEC").ok().and_then(|value| {
+            let per_sec = value.parse::<f64>().ok().filter(|rate| rate.is_finite() && *rate > 0.0);
+            if per_sec.is_none() {
+                warn!("RATE_LIMIT_PER_SEC={:?} is not a positive number; rate limiting is off", value);
+            }
+            per_sec
+        });
+        RateLimiter::new(per_sec)
+    }
+
+    /// Takes a token from `client`'s bucket, or returns false when it is empty.
+    fn allow(&self, client: IpAddr) -> bool {
+        let Some(per_sec) = self.per_sec else {
+            return true;
+        };
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            buckets.retain(|_, bucket| bucket.tokens + now.duration_since(bucket.refilled).as_secs_f64() * per_sec < per_sec);
+        }
+        let bucket = buckets.entry(client).or_insert(Bucket { tokens: per_sec, refilled: now });
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_sec).min(per_sec);
+        bucket.refilled = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Request guard for the API routes: fails with 429, counted in
+/// `http_rate_limited_total`, once the client has used up its requests. Requests
+/// without a known client IP are never limited.
+pub struct WithinRateLimit;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for WithinRateLimit {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let (Some(limiter), Some(client)) = (request.rocket().state::<RateLimiter>(), request.client_ip()) else {
+            return Outcome::Success(WithinRateLimit);
+        };
+        if limiter.allow(client) {
+            return Outcome::Success(WithinRateLimit);
+        }
+        if let Some(metrics) = request.rocket().state::<AppMetrics>() {
+            metrics.http_rate_limited_total.with_label_values(&[normalized_path(request)]).inc();
+        }
+        Outcome::Error((Status::TooManyRequests, ()))
+    }
+}
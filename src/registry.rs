@@ -0,0 +1,216 @@
+use prometheus::core::Collector;
+use prometheus::{Counter, CounterVec, Gauge, GaugeVec, Histogram, HistogramOpts, Opts, Registry};
+
+use crate::config::MetricsConfig;
+use crate::fairing::PrometheusFairing;
+
+/// The application, process and system collectors. Handlers reach them through
+/// managed state; the HTTP collectors live in `PrometheusFairing`.
+#[derive(Clone)]
+pub struct AppMetrics {
+    pub process_cpu_usage: Gauge,
+    pub system_load_average_1m: Gauge,
+    pub memory_used_bytes: Gauge,
+    pub memory_total_bytes: Gauge,
+    pub memory_free_bytes: Gauge,
+    pub swap_total_bytes: Gauge,
+    pub swap_used_bytes: Gauge,
+    pub process_open_fds: Gauge,
+    pub process_max_fds: Gauge,
+    pub disk_total_bytes: Gauge,
+    pub disk_free_bytes: Gauge,
+    pub disk_used_bytes: Gauge,
+    pub threads_live: Gauge,
+    pub process_uptime_seconds: Gauge,
+    pub tokio_worker_threads: Gauge,
+    pub tokio_active_tasks: Gauge,
+    pub build_info: GaugeVec,
+    pub items_count: Gauge,
+    pub items_tagged_total: Counter,
+    pub items_autonamed_total: Counter,
+    pub items_evicted_total: Counter,
+    pub items_store_bytes: Gauge,
+    pub service_ready: Gauge,
+    pub http_unmatched_requests_total: CounterVec,
+    pub metrics_scrape_duration_seconds: Histogram,
+    pub item_name_length_chars: Histogram,
+    pub http_validation_errors_total: CounterVec,
+    pub http_rate_limited_total: CounterVec,
+    pub http_cache_hits_total: Counter,
+    pub http_json_errors_total: CounterVec,
+    pub http_body_too_large_total: CounterVec,
+    pub items_list_page_size: Histogram,
+    pub items_search_total: Counter,
+    pub items_search_results: Histogram,
+    pub items_lock_wait_seconds: Histogram,
+    pub items_lock_hold_seconds: Histogram,
+    pub items_lock_poisoned_total: Counter,
+    pub items_idempotent_replays_total: Counter,
+    pub maintenance_mode: Gauge,
+}
+
+impl AppMetrics {
+    /// Creates the collectors with `namespace` prefixed to their names and
+    /// registers them with `registry`.
+    pub fn new(registry: &Registry, namespace: &str) -> prometheus::Result<Self> {
+        let opts = |name: &str, help: &str| Opts::new(name, help).namespace(namespace);
+        let histogram_opts = |name: &str, help: &str| HistogramOpts::new(name, help).namespace(namespace);
+        let metrics = AppMetrics {
+            process_cpu_usage: Gauge::with_opts(opts("process_cpu_usage", "The recent cpu usage for the process, as a fraction of one core"))?,
+            system_load_average_1m: Gauge::with_opts(opts("system_load_average_1m", "The system load average over the last minute"))?,
+            memory_used_bytes: Gauge::with_opts(opts("memory_used_bytes", "The amount of used memory"))?,
+            memory_total_bytes: Gauge::with_opts(opts("memory_total_bytes", "The total amount of physical memory"))?,
+            memory_free_bytes: Gauge::with_opts(opts("memory_free_bytes", "The amount of free memory"))?,
+            swap_total_bytes: Gauge::with_opts(opts("swap_total_bytes", "The total amount of swap space"))?,
+            swap_used_bytes: Gauge::with_opts(opts("swap_used_bytes", "The amount of used swap space"))?,
+            process_open_fds: Gauge::with_opts(opts("process_open_fds", "Number of open file descriptors"))?,
+            process_max_fds: Gauge::with_opts(opts("process_max_fds", "Soft limit on open file descriptors (RLIMIT_NOFILE)"))?,
+            disk_total_bytes: Gauge::with_opts(opts("disk_total_bytes", "Size of the filesystem holding the working directory"))?,
+            disk_free_bytes: Gauge::with_opts(opts("disk_free_bytes", "Space available to the process on the filesystem holding the working directory"))?,
+            disk_used_bytes: Gauge::with_opts(opts("disk_used_bytes", "Space not available on the filesystem holding the working directory"))?,
+            threads_live: Gauge::with_opts(opts("threads_live", "The current number of live threads"))?,
+            process_uptime_seconds: Gauge::with_opts(opts("process_uptime_seconds", "Seconds since the process started"))?,
+            tokio_worker_threads: Gauge::with_opts(opts("tokio_worker_threads", "Number of worker threads of the Tokio runtime serving requests"))?,
+            tokio_active_tasks: Gauge::with_opts(opts("tokio_active_tasks", "Number of tasks currently alive in the Tokio runtime"))?,
+            build_info: GaugeVec::new(
+                opts("build_info", "Build metadata for the running binary, always 1"),
+                &["version", "rustc", "git_sha"]
+            )?,
+            items_count: Gauge::with_opts(opts("items_count", "The current number of stored items"))?,
+            items_tagged_total: Counter::with_opts(opts("items_tagged_total", "Items created with at least one tag"))?,
+            items_autonamed_total: Counter::with_opts(opts("items_autonamed_total", "Items created without a body and named after DEFAULT_ITEM_NAME"))?,
+            items_evicted_total: Counter::with_opts(opts("items_evicted_total", "Items removed by the background sweep for outliving ITEM_TTL_SECONDS"))?,
+            items_store_bytes: Gauge::with_opts(opts("items_store_bytes", "Estimated bytes held by the in-memory item store"))?,
+            service_ready: Gauge::with_opts(opts("service_ready", "1 when the service is ready to serve traffic (see /readyz), 0 otherwise"))?,
+            http_unmatched_requests_total: CounterVec::new(
+                opts("http_unmatched_requests_total", "Requests that matched no route"),
+                &["method", "status"]
+            )?,
+            metrics_scrape_duration_seconds: Histogram::with_opts(
+                histogram_opts("metrics_scrape_duration_seconds", "Time spent in the /metrics handler gathering and encoding the registry; system metrics are refreshed in the background")
+            )?,
+            item_name_length_chars: Histogram::with_opts(
+                histogram_opts("item_name_length_chars", "Length in characters of item names accepted by create and update")
+                    .buckets(vec![1.0, 8.0, 16.0, 32.0, 64.0, 128.0, 256.0])
+            )?,
+            http_validation_errors_total: CounterVec::new(
+                opts("http_validation_errors_total", "Requests rejected for failing item validation"),
+                &["path"]
+            )?,
+            http_rate_limited_total: CounterVec::new(
+                opts("http_rate_limited_total", "Requests rejected with 429 for exceeding RATE_LIMIT_PER_SEC"),
+                &["path"]
+            )?,
+            http_cache_hits_total: Counter::with_opts(
+                opts("http_cache_hits_total", "Item reads answered 304 Not Modified because If-None-Match matched the ETag")
+            )?,
+            http_json_errors_total: CounterVec::new(
+                opts("http_json_errors_total", "Requests whose body failed to deserialize (400 or 422 before the handler ran), except for unknown fields, which count as validation errors"),
+                &["path"]
+            )?,
+            http_body_too_large_total: CounterVec::new(
+                opts("http_body_too_large_total", "Requests rejected with 413 for a body over MAX_BODY_BYTES"),
+                &["path"]
+            )?,
+            items_list_page_size: Histogram::with_opts(
+                histogram_opts("items_list_page_size", "Number of items returned per GET /items page")
+                    .buckets(vec![0.0, 1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0])
+            )?,
+            items_search_total: Counter::with_opts(opts("items_search_total", "Searches served by GET /items/search"))?,
+            items_search_results: Histogram::with_opts(
+                histogram_opts("items_search_results", "Number of items matched per GET /items/search")
+                    .buckets(vec![0.0, 1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0])
+            )?,
+            items_lock_wait_seconds: Histogram::with_opts(
+                histogram_opts("items_lock_wait_seconds", "Time handlers spent waiting to acquire the items store lock")
+                    .buckets(vec![0.00001, 0.0001, 0.001, 0.01, 0.1, 1.0])
+            )?,
+            items_lock_hold_seconds: Histogram::with_opts(
+                histogram_opts("items_lock_hold_seconds", "Time handlers held the items store lock, from acquiring it to releasing it")
+                    .buckets(vec![0.00001, 0.0001, 0.001, 0.01, 0.1, 1.0])
+            )?,
+            items_lock_poisoned_total: Counter::with_opts(
+                opts("items_lock_poisoned_total", "Times the items store lock was found poisoned by a panicking handler and recovered")
+            )?,
+            items_idempotent_replays_total: Counter::with_opts(
+                opts("items_idempotent_replays_total", "Item creations answered with the stored response of an earlier request with the same Idempotency-Key")
+            )?,
+            maintenance_mode: Gauge::with_opts(opts("maintenance_mode", "1 while maintenance mode has the item routes answering 503, 0 otherwise"))?,
+        };
+
+        register(registry, &metrics.process_cpu_usage)?;
+        register(registry, &metrics.system_load_average_1m)?;
+        register(registry, &metrics.memory_used_bytes)?;
+        register(registry, &metrics.memory_total_bytes)?;
+        register(registry, &metrics.memory_free_bytes)?;
+        register(registry, &metrics.swap_total_bytes)?;
+        register(registry, &metrics.swap_used_bytes)?;
+        // Only Linux has /proc to read these from
+        if cfg!(target_os = "linux") {
+            register(registry, &metrics.process_open_fds)?;
+            register(registry, &metrics.process_max_fds)?;
+        }
+        register(registry, &metrics.disk_total_bytes)?;
+        register(registry, &metrics.disk_free_bytes)?;
+        register(registry, &metrics.disk_used_bytes)?;
+        register(registry, &metrics.threads_live)?;
+        register(registry, &metrics.process_uptime_seconds)?;
+        register(registry, &metrics.tokio_worker_threads)?;
+        register(registry, &metrics.tokio_active_tasks)?;
+        register(registry, &metrics.build_info)?;
+        register(registry, &metrics.items_count)?;
+        register(registry, &metrics.items_tagged_total)?;
+        register(registry, &metrics.items_autonamed_total)?;
+        register(registry, &metrics.items_evicted_total)?;
+        register(registry, &metrics.items_store_bytes)?;
+        register(registry, &metrics.service_ready)?;
+        register(registry, &metrics.items_list_page_size)?;
+        register(registry, &metrics.items_search_total)?;
+        register(registry, &metrics.items_search_results)?;
+        register(registry, &metrics.http_validation_errors_total)?;
+        register(registry, &metrics.http_rate_limited_total)?;
+        register(registry, &metrics.http_cache_hits_total)?;
+        register(registry, &metrics.http_json_errors_total)?;
+        register(registry, &metrics.http_body_too_large_total)?;
+        register(registry, &metrics.item_name_length_chars)?;
+        register(registry, &metrics.http_unmatched_requests_total)?;
+        register(registry, &metrics.metrics_scrape_duration_seconds)?;
+        register(registry, &metrics.items_lock_wait_seconds)?;
+        register(registry, &metrics.items_lock_hold_seconds)?;
+        register(registry, &metrics.items_lock_poisoned_total)?;
+        register(registry, &metrics.items_idempotent_replays_total)?;
+        register(registry, &metrics.maintenance_mode)?;
+
+        metrics.build_info.with_label_values(&[
+            env!("CARGO_PKG_VERSION"),
+            option_env!("RUSTC_VERSION").unwrap_or("unknown"),
+            option_env!("GIT_SHA").unwrap_or("unknown"),
+        ]).set(1.0);
+        Ok(metrics)
+    }
+}
+
+/// Registers `collector` with `registry`. Errors name the collector's metrics,
+/// since the `prometheus` error (e.g. `AlreadyReg` for a duplicate) doesn't.
+pub fn register<C: Collector + Clone + 'static>(registry: &Registry, collector: &C) -> prometheus::Result<()> {
+    registry.register(Box::new(collector.clone())).map_err(|e| {
+        let names: Vec<&str> = collector.desc().iter().map(|desc| desc.fq_name.as_str()).collect();
+        prometheus::Error::Msg(format!("could not register {}: {}", names.join(", "), e))
+    })
+}
+
+/// A registry with every collector of the app registered, and handles to them.
+pub struct Metrics {
+    pub registry: Registry,
+    pub app: AppMetrics,
+    pub http: PrometheusFairing,
+}
+
+/// Builds a fresh `Registry` holding the app and HTTP collectors described by
+/// `config`. Each call is independent, so separate instances never share counts.
+pub fn build_registry(config: &MetricsConfig) -> prometheus::Result<Metrics> {
+    let registry = Registry::new();
+    let app = AppMetrics::new(&registry, &config.namespace)?;
+    let http = PrometheusFairing::new(&registry, config)?;
+    Ok(Metrics { registry, app, http })
+}
@@ -0,0 +1,63 @@
+use std::net::UdpSocket;
+use std::time::Instant;
+
+use cadence::prelude::*;
+use cadence::{MetricResult, StatsdClient, UdpMetricSink};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request, Response};
+
+use crate::fairing::{is_instrumented, normalized_path, request_method};
+
+struct StatsdStart(Instant);
+
+/// Mirrors `http_requests_total` and the request duration to a StatsD agent as
+/// a counter and a timer, tagged DogStatsD-style with `method`, `path` and
+/// `status`. Metrics go out over UDP and are dropped when the agent is down.
+pub struct StatsdFairing {
+    client: StatsdClient,
+}
+
+impl StatsdFairing {
+    /// Sends to `addr` (`host:port`), prefixing metric names with `namespace`.
+    pub fn new(addr: &str, namespace: &str) -> MetricResult<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_nonblocking(true)?;
+        let sink = UdpMetricSink::from(addr, socket)?;
+        Ok(StatsdFairing { client: StatsdClient::from_sink(namespace, sink) })
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for StatsdFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "StatsD HTTP metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| StatsdStart(Instant::now()));
+        request_method(request);
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if !is_instrumented(request) {
+            return;
+        }
+        let duration = request.local_cache(|| StatsdStart(Instant::now())).0.elapsed();
+        let method = request_method(request).as_str();
+        let status = response.status().code.to_string();
+        let path = normalized_path(request);
+        let _ = self.client.count_with_tags("http_requests_total", 1)
+            .with_tag("method", method)
+            .with_tag("path", path)
+            .with_tag("status", &status)
+            .try_send();
+        let _ = self.client.time_with_tags("http_request_duration", duration)
+            .with_tag("method", method)
+            .with_tag("path", path)
+            .with_tag("status", &status)
+            .try_send();
+    }
+}
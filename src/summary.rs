@@ -0,0 +1,114 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use prometheus::core::{Collector, Desc, Describer};
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType, Quantile};
+use prometheus::Opts;
+
+/// Quantiles reported for every series.
+pub const QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// Quantiles are computed over this many of a series' latest observations.
+const MAX_SAMPLES: usize = 1024;
+
+#[derive(Default)]
+struct Window {
+    samples: VecDeque<f64>,
+    sum: f64,
+    count: u64,
+}
+
+/// One labelled series of a `SummaryVec`.
+#[derive(Clone, Default)]
+pub struct Summary(Arc<Mutex<Window>>);
+
+impl Summary {
+    pub fn observe(&self, value: f64) {
+        let mut window = self.0.lock().unwrap();
+        window.sum += value;
+        window.count += 1;
+        if window.samples.len() == MAX_SAMPLES {
+            window.samples.pop_front();
+        }
+        window.samples.push_back(value);
+    }
+}
+
+/// A summary family with client-side `QUANTILES`. The `prometheus` crate only
+/// has histograms, so this collects its own series; `_sum` and `_count` cover
+/// every observation, the quantiles the latest `MAX_SAMPLES`.
+#[derive(Clone)]
+pub struct SummaryVec {
+    desc: Desc,
+    series: Arc<Mutex<HashMap<Vec<String>, Summary>>>,
+}
+
+impl SummaryVec {
+    pub fn new(opts: Opts, label_names: &[&str]) -> prometheus::Result<Self> {
+        let opts = opts.variable_labels(label_names.iter().map(|name| name.to_string()).collect());
+        Ok(SummaryVec { desc: opts.describe()?, series: Arc::default() })
+    }
+
+    pub fn with_label_values(&self, values: &[&str]) -> Summary {
+        let values: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+        self.series.lock().unwrap().entry(values).or_default().clone()
+    }
+
+    /// Drops every series, like `MetricVec::reset`.
+    pub fn reset(&self) {
+        self.series.lock().unwrap().clear();
+    }
+}
+
+/// The nearest-rank `quantile` of `sorted`, NaN when there are no samples.
+fn quantile_value(sorted: &[f64], quantile: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+    let rank = (quantile * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+impl Collector for SummaryVec {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut family = MetricFamily::default();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(MetricType::SUMMARY);
+        for (values, summary) in self.series.lock().unwrap().iter() {
+            let window = summary.0.lock().unwrap();
+            let mut sorted: Vec<f64> = window.samples.iter().copied().collect();
+            sorted.sort_by(f64::total_cmp);
+
+            let mut metric = Metric::default();
+            let mut labels: Vec<LabelPair> = self.desc.variable_labels.iter()
+                .zip(values)
+                .map(|(name, value)| {
+                    let mut pair = LabelPair::default();
+                    pair.set_name(name.clone());
+                    pair.set_value(value.clone());
+                    pair
+                })
+                .collect();
+            labels.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+            for pair in labels {
+                metric.mut_label().push(pair);
+            }
+            let proto = metric.mut_summary();
+            proto.set_sample_sum(window.sum);
+            proto.set_sample_count(window.count);
+            for quantile in QUANTILES {
+                let mut line = Quantile::default();
+                line.set_quantile(quantile);
+                line.set_value(quantile_value(&sorted, quantile));
+                proto.mut_quantile().push(line);
+            }
+            family.mut_metric().push(metric);
+        }
+        vec![family]
+    }
+}
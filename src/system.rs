@@ -0,0 +1,111 @@
+use std::sync::{Arc, Mutex};
+
+use sys_info::{loadavg, mem_info};
+use sysinfo::{Disks, ProcessRefreshKind, ProcessesToUpdate, System};
+
+/// Physical memory and swap, in bytes.
+pub struct MemInfo {
+    pub total: u64,
+    pub free: u64,
+    pub swap_total: u64,
+    pub swap_free: u64,
+}
+
+/// Source of the readings behind the process and system gauges. `None` means a
+/// reading isn't available on this platform or failed; its gauges then keep
+/// their previous values.
+pub trait SystemInfoProvider: Send + Sync {
+    /// CPU time of this process since the previous call, as a fraction of one core.
+    fn cpu_usage(&self) -> Option<f64>;
+    /// The one-minute load average.
+    fn load_avg(&self) -> Option<f64>;
+    fn mem_info(&self) -> Option<MemInfo>;
+    /// OS threads of this process.
+    fn thread_count(&self) -> Option<usize>;
+    fn open_fds(&self) -> Option<usize>;
+    /// The soft open file limit; `None` when unlimited.
+    fn max_fds(&self) -> Option<u64>;
+    /// Total and available bytes of the filesystem holding the working directory.
+    fn working_dir_disk(&self) -> Option<(u64, u64)>;
+}
+
+/// The provider the system metrics are read from, kept in managed state.
+#[derive(Clone)]
+pub struct SystemInfo(pub Arc<dyn SystemInfoProvider>);
+
+/// Reads the real host through sys-info, sysinfo and, on Linux, `/proc`.
+#[derive(Default)]
+pub struct HostSystem {
+    /// sysinfo keeps the prior CPU sample here, so the very first
+    /// `cpu_usage` reports 0.
+    system: Mutex<System>,
+}
+
+impl SystemInfoProvider for HostSystem {
+    fn cpu_usage(&self) -> Option<f64> {
+        let pid = sysinfo::get_current_pid().ok()?;
+        let mut system = self.system.lock().unwrap();
+        system.refresh_processes_specifics(
+            ProcessesToUpdate::Some(&[pid]),
+            true,
+            ProcessRefreshKind::nothing().with_cpu(),
+        );
+        let usage = system.process(pid)?.cpu_usage() as f64 / 100.0;
+        usage.is_finite().then_some(usage)
+    }
+
+    fn load_avg(&self) -> Option<f64> {
+        loadavg().ok().map(|load| load.one)
+    }
+
+    // mem_info() reports KiB
+    fn mem_info(&self) -> Option<MemInfo> {
+        let mem = mem_info().ok()?;
+        Some(MemInfo {
+            total: mem.total * 1024,
+            free: mem.free * 1024,
+            swap_total: mem.swap_total * 1024,
+            swap_free: mem.swap_free * 1024,
+        })
+    }
+
+    /// Reads `/proc/self/task` where it exists and falls back to sysinfo's
+    /// task list elsewhere.
+    fn thread_count(&self) -> Option<usize> {
+        if let Ok(tasks) = std::fs::read_dir("/proc/self/task") {
+            return Some(tasks.count());
+        }
+        let pid = sysinfo::get_current_pid().ok()?;
+        let mut system = self.system.lock().unwrap();
+        system.refresh_processes_specifics(
+            ProcessesToUpdate::Some(&[pid]),
+            true,
+            ProcessRefreshKind::nothing().with_tasks(),
+        );
+        system.process(pid)?.tasks().map(|tasks| tasks.len())
+    }
+
+    /// Counts the entries of `/proc/self/fd`, which includes the descriptor of
+    /// the directory being read.
+    fn open_fds(&self) -> Option<usize> {
+        Some(std::fs::read_dir("/proc/self/fd").ok()?.count())
+    }
+
+    /// From the "Max open files" row of `/proc/self/limits`.
+    fn max_fds(&self) -> Option<u64> {
+        let limits = std::fs::read_to_string("/proc/self/limits").ok()?;
+        let row = limits.lines().find(|line| line.starts_with("Max open files"))?;
+        row["Max open files".len()..].split_whitespace().next()?.parse().ok()
+    }
+
+    /// Uses the filesystem mounted closest to the working directory, or `None`
+    /// where the mount list can't be read.
+    fn working_dir_disk(&self) -> Option<(u64, u64)> {
+        let cwd = std::env::current_dir().ok()?;
+        let disks = Disks::new_with_refreshed_list();
+        let disk = disks.list().iter()
+            .filter(|disk| cwd.starts_with(disk.mount_point()))
+            .max_by_key(|disk| disk.mount_point().as_os_str().len())?;
+        Some((disk.total_space(), disk.available_space()))
+    }
+}
@@ -0,0 +1,1885 @@
+//! Tests of the assembled app through Rocket's local client. The app reads its
+//! configuration from the environment, so every test that builds it holds
+//! `ENV` while the app is alive; tests of the fairing alone build a bare
+//! Rocket around it instead and can run in parallel.
+
+use std::collections::HashSet;
+use std::env;
+use std::fmt::Debug;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream, UdpSocket};
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Barrier, Mutex, MutexGuard, PoisonError};
+
+use flate2::read::GzDecoder;
+use opentelemetry_sdk::metrics::InMemoryMetricExporter;
+use prometheus::{Gauge, IntGauge, TextEncoder};
+use rocket::config::LogLevel;
+use rocket::futures::future::join_all;
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::asynchronous;
+use rocket::local::blocking::{Client, LocalRequest};
+use rocket::{Route, Shutdown};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+use serde_json::Value;
+
+use super::*;
+use crate::config::AccessLog;
+use crate::exemplars::Exemplars;
+use crate::system::MemInfo;
+
+static ENV: Mutex<()> = Mutex::new(());
+
+/// Environment variables set for one test and removed again on drop, with
+/// `ENV` held in between. Rocket's own logging is switched off.
+struct TestEnv {
+    names: Vec<&'static str>,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl TestEnv {
+    fn set(vars: &[(&'static str, &str)]) -> Self {
+        let lock = ENV.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut names = vec!["ROCKET_LOG_LEVEL"];
+        env::set_var("ROCKET_LOG_LEVEL", "off");
+        for (name, value) in vars {
+            env::set_var(name, value);
+            names.push(name);
+        }
+        TestEnv { names, _lock: lock }
+    }
+}
+
+impl Drop for TestEnv {
+    fn drop(&mut self) {
+        for name in &self.names {
+            env::remove_var(name);
+        }
+    }
+}
+
+/// The app as `rocket()` builds it, configured by the variables it was created
+/// with. `client` is declared first so the app is gone before `ENV` is released.
+struct TestApp {
+    client: Client,
+    _env: TestEnv,
+}
+
+impl TestApp {
+    fn new(vars: &[(&'static str, &str)]) -> Self {
+        TestApp::with_system(vars, host())
+    }
+
+    fn with_system(vars: &[(&'static str, &str)], system: SystemInfo) -> Self {
+        let env = TestEnv::set(vars);
+        let client = Client::tracked(build(system)).expect("the app ignites");
+        TestApp { client, _env: env }
+    }
+
+    fn metrics(&self) -> &AppMetrics {
+        self.rocket().state::<AppMetrics>().unwrap()
+    }
+
+    fn scrape(&self) -> String {
+        let response = self.get("/metrics").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        response.into_string().unwrap()
+    }
+
+    /// A scrape taken right after refreshing the system gauges, which otherwise
+    /// only change on the background interval.
+    fn refreshed_scrape(&self) -> String {
+        update_system_metrics(&*self.rocket().state::<SystemInfo>().unwrap().0, self.metrics());
+        self.scrape()
+    }
+
+    /// Creates an item named `name` and returns the response body.
+    fn create(&self, name: &str) -> Value {
+        let response = post_json(self.post("/items"), &json!({ "name": name }).to_string()).dispatch();
+        assert_eq!(response.status(), Status::Created);
+        response.into_json().unwrap()
+    }
+}
+
+impl Deref for TestApp {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        &self.client
+    }
+}
+
+fn host() -> SystemInfo {
+    SystemInfo(Arc::new(HostSystem::default()))
+}
+
+fn quiet() -> rocket::Config {
+    rocket::Config { log_level: LogLevel::Off, ..rocket::Config::debug_default() }
+}
+
+/// `request` with a JSON `body`. The local client doesn't add `Content-Length`
+/// the way an HTTP client does.
+fn post_json<'c>(request: LocalRequest<'c>, body: &str) -> LocalRequest<'c> {
+    request.header(ContentType::JSON)
+        .header(Header::new("Content-Length", body.len().to_string()))
+        .body(body)
+}
+
+/// The value of the sample `series` (its name and labels as the text format
+/// writes them, labels sorted by name) in `body`.
+fn sample(body: &str, series: &str) -> Option<f64> {
+    body.lines().find_map(|line| line.strip_prefix(series)?.strip_prefix(' ')?.split(' ').next()?.parse().ok())
+}
+
+/// Calls `check` every 50 ms until it holds, failing after five seconds.
+fn eventually(what: &str, mut check: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !check() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn http(client: &Client) -> &PrometheusFairing {
+    client.rocket().state::<PrometheusFairing>().unwrap()
+}
+
+/// A bare Rocket serving `routes` with only `PrometheusFairing` attached, for
+/// tests of the fairing that need routes of their own.
+fn instrumented(config: &MetricsConfig, routes: Vec<Route>) -> Client {
+    let metrics = registry::build_registry(config).unwrap();
+    let rocket = rocket::custom(quiet()).manage(metrics.registry.clone()).mount("/", routes);
+    Client::tracked(metrics.http.attach(rocket)).unwrap()
+}
+
+/// The text exposition of the registry a client manages.
+fn encode(client: &Client) -> String {
+    TextEncoder::new().encode_to_string(&client.rocket().state::<Registry>().unwrap().gather()).unwrap()
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("rocket-prometheus-{}-{}", std::process::id(), name))
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// A `GET` with `headers` over a real connection to `127.0.0.1:port`, retried
+/// until the server accepts it. Returns the status code, the raw header block
+/// and the raw body, which HTTP/1.0 keeps free of chunked encoding.
+fn http_get(port: u16, path: &str, headers: &[(&str, &str)]) -> (u16, String, String) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut stream = loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => break stream,
+            Err(e) if Instant::now() < deadline => {
+                let _ = e;
+                thread::sleep(Duration::from_millis(50));
+            }
+            Err(e) => panic!("could not connect to port {}: {}", port, e),
+        }
+    };
+    let headers: String = headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect();
+    write!(stream, "GET {} HTTP/1.0\r\nHost: localhost\r\n{}\r\n", path, headers).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let code = response.split(' ').nth(1).and_then(|code| code.parse().ok()).unwrap_or(0);
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    (code, head.to_string(), body.to_string())
+}
+
+/// The app built from `vars` and launched on a real port, shut down on drop.
+struct Launched {
+    shutdown: Shutdown,
+    port: u16,
+    _env: TestEnv,
+}
+
+impl Launched {
+    fn new(vars: &[(&'static str, &str)]) -> Self {
+        let port = free_port();
+        let mut vars = vars.to_vec();
+        let port_var = port.to_string();
+        vars.extend([("BIND_ADDRESS", "127.0.0.1"), ("PORT", port_var.as_str())]);
+        let env = TestEnv::set(&vars);
+        let rocket = build(host());
+        let (sender, shutdown) = mpsc::channel();
+        thread::spawn(move || rocket::execute(async move {
+            let rocket = rocket.ignite().await.expect("the app ignites");
+            sender.send(rocket.shutdown()).unwrap();
+            let _ = rocket.launch().await;
+        }));
+        let shutdown = shutdown.recv().unwrap();
+        Launched { shutdown, port, _env: env }
+    }
+}
+
+impl Drop for Launched {
+    fn drop(&mut self) {
+        self.shutdown.clone().notify();
+    }
+}
+
+/// Fixed readings; `mem_info` calls are counted.
+#[derive(Default)]
+struct FakeSystem {
+    mem_reads: AtomicUsize,
+}
+
+impl SystemInfoProvider for FakeSystem {
+    fn cpu_usage(&self) -> Option<f64> {
+        Some(0.25)
+    }
+
+    fn load_avg(&self) -> Option<f64> {
+        Some(1.5)
+    }
+
+    fn mem_info(&self) -> Option<MemInfo> {
+        self.mem_reads.fetch_add(1, Ordering::SeqCst);
+        Some(MemInfo { total: 8_000, free: 3_000, swap_total: 2_000, swap_free: 500 })
+    }
+
+    fn thread_count(&self) -> Option<usize> {
+        Some(7)
+    }
+
+    fn open_fds(&self) -> Option<usize> {
+        Some(12)
+    }
+
+    fn max_fds(&self) -> Option<u64> {
+        Some(1024)
+    }
+
+    fn working_dir_disk(&self) -> Option<(u64, u64)> {
+        Some((10_000, 4_000))
+    }
+}
+
+/// A writer collecting everything written to it, for capturing output.
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Captured {
+    fn lines(&self) -> Vec<String> {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap().lines().map(str::to_string).collect()
+    }
+}
+
+impl Write for Captured {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+
+/// Span fields recorded by the request spans, by name.
+#[derive(Clone, Default)]
+struct SpanFields(Arc<Mutex<HashMap<String, String>>>);
+
+impl Visit for SpanFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.lock().unwrap().insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0.lock().unwrap().insert(field.name().to_string(), format!("{:?}", value));
+    }
+}
+
+impl<S: Subscriber> Layer<S> for SpanFields {
+    fn on_new_span(&self, attributes: &Attributes<'_>, _: &Id, _: Context<'_, S>) {
+        attributes.record(&mut self.clone());
+    }
+
+    fn on_record(&self, _: &Id, values: &Record<'_>, _: Context<'_, S>) {
+        values.record(&mut self.clone());
+    }
+}
+
+fn poison(items: &Items) {
+    thread::scope(|scope| {
+        let _ = scope.spawn(|| {
+            let _map = items.map.write().unwrap();
+            panic!("poisoning the items lock");
+        }).join();
+    });
+}
+
+#[get("/hello")]
+fn hello() -> &'static str {
+    "hello"
+}
+
+#[get("/slow")]
+fn slow() -> &'static str {
+    thread::sleep(Duration::from_millis(30));
+    "slow"
+}
+
+/// Answers with the in-progress gauges as seen while the request is served.
+#[get("/in-flight")]
+fn in_flight(http: &State<PrometheusFairing>) -> String {
+    let by_path = http.requests_in_progress_by_path.with_label_values(&["GET", "/in-flight"]).get();
+    format!("{} {}", http.requests_in_progress.get(), by_path)
+}
+
+/// Like `slow`, but waits at an await point, where a timeout can stop it.
+#[get("/sleeps")]
+async fn sleeps() -> &'static str {
+    rocket::tokio::time::sleep(Duration::from_millis(500)).await;
+    "slept"
+}
+
+#[get("/panics")]
+fn panics() -> &'static str {
+    panic!("handler failure")
+}
+
+/// Encodes the managed registry the way `/metrics` encodes the app's.
+#[get("/families")]
+fn families(format: MetricsFormat, gzip: AcceptsGzip, registry: &State<Registry>) -> MetricsBody {
+    MetricsBody::new(registry.gather(), format, &Exemplars::default(), gzip.0)
+}
+
+#[get("/big?<gzip>")]
+fn big(gzip: bool, registry: &State<Registry>) -> MetricsBody {
+    MetricsBody::new(registry.gather(), MetricsFormat::Text, &Exemplars::default(), gzip)
+}
+
+#[rocket::async_test]
+async fn concurrent_requests_record_their_own_status() {
+    let _env = TestEnv::set(&[]);
+    let client = asynchronous::Client::tracked(build(host())).await.unwrap();
+    let requests = (0..50).map(|n| {
+        let client = &client;
+        async move {
+            let uri = if n % 2 == 0 { "/".to_string() } else { format!("/items/{}", n) };
+            client.get(uri).dispatch().await.status()
+        }
+    });
+    let statuses = join_all(requests).await;
+    assert_eq!(statuses.iter().filter(|status| **status == Status::Ok).count(), 25);
+    assert_eq!(statuses.iter().filter(|status| **status == Status::NotFound).count(), 25);
+
+    let body = client.get("/metrics").dispatch().await.into_string().await.unwrap();
+    assert_eq!(sample(&body, r#"http_requests_total{method="GET",path="/",status="200"}"#), Some(25.0));
+    assert_eq!(sample(&body, r#"http_requests_total{method="GET",path="/items/<id>",status="404"}"#), Some(25.0));
+}
+
+#[test]
+fn records_the_status_the_response_went_out_with() {
+    let app = TestApp::new(&[]);
+    let response = post_json(app.post("/items"), r#"{"title": "no name"}"#).dispatch();
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    assert_eq!(app.get("/nope").dispatch().status(), Status::NotFound);
+
+    let body = app.scrape();
+    assert_eq!(sample(&body, r#"http_requests_total{method="POST",path="/items",status="422"}"#), Some(1.0));
+    assert_eq!(sample(&body, r#"http_requests_total{method="GET",path="unmatched",status="404"}"#), Some(1.0));
+}
+
+#[test]
+fn labels_requests_with_the_route_template() {
+    let app = TestApp::new(&[]);
+    app.create("a");
+    app.create("b");
+    app.get("/items/1").dispatch();
+    app.get("/items/2").dispatch();
+
+    let body = app.scrape();
+    assert_eq!(sample(&body, r#"http_requests_total{method="GET",path="/items/<id>",status="200"}"#), Some(2.0));
+    assert!(!body.contains(r#"path="/items/1""#));
+}
+
+#[test]
+fn items_count_follows_creates_and_deletes() {
+    let app = TestApp::new(&[]);
+    app.create("a");
+    app.create("b");
+    assert_eq!(sample(&app.scrape(), "items_count"), Some(2.0));
+    app.delete("/items/1").dispatch();
+    assert_eq!(sample(&app.scrape(), "items_count"), Some(1.0));
+}
+
+#[test]
+fn uptime_grows_between_scrapes() {
+    let app = TestApp::new(&[]);
+    let first = sample(&app.scrape(), "process_uptime_seconds").unwrap();
+    thread::sleep(Duration::from_millis(20));
+    let second = sample(&app.scrape(), "process_uptime_seconds").unwrap();
+    assert!(first >= 0.0, "{}", first);
+    assert!(second > first, "{} then {}", first, second);
+}
+
+#[test]
+fn build_info_reports_the_version() {
+    let body = TestApp::new(&[]).scrape();
+    let build_info = body.lines().find(|line| line.starts_with("build_info{")).unwrap();
+    assert!(build_info.contains(&format!(r#"version="{}""#, env!("CARGO_PKG_VERSION"))), "{}", build_info);
+    assert!(build_info.ends_with(" 1"), "{}", build_info);
+}
+
+#[test]
+fn cpu_usage_stays_within_the_cores() {
+    let app = TestApp::new(&[]);
+    let cores = thread::available_parallelism().unwrap().get() as f64;
+    for _ in 0..3 {
+        let body = app.refreshed_scrape();
+        let usage = sample(&body, "process_cpu_usage").unwrap();
+        assert!((0.0..=cores).contains(&usage), "{} of {} cores", usage, cores);
+        assert!(sample(&body, "system_load_average_1m").is_some_and(|load| load >= 0.0));
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn threads_live_counts_spawned_threads() {
+    let app = TestApp::new(&[]);
+    let before = sample(&app.refreshed_scrape(), "threads_live").unwrap();
+    let release = Arc::new(Barrier::new(33));
+    let threads: Vec<_> = (0..32).map(|_| {
+        let release = release.clone();
+        thread::spawn(move || {
+            release.wait();
+        })
+    }).collect();
+    let during = sample(&app.refreshed_scrape(), "threads_live").unwrap();
+    release.wait();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert!(during > before, "{} threads, then {} with 32 more", before, during);
+}
+
+#[test]
+fn memory_gauges_are_in_bytes() {
+    let body = TestApp::new(&[]).refreshed_scrape();
+    let total = sample(&body, "memory_total_bytes").unwrap();
+    assert_eq!(total, HostSystem::default().mem_info().unwrap().total as f64);
+    assert_eq!(sample(&body, "memory_used_bytes").unwrap() + sample(&body, "memory_free_bytes").unwrap(), total);
+    assert!(sample(&body, "swap_used_bytes").unwrap() <= sample(&body, "swap_total_bytes").unwrap());
+}
+
+#[test]
+fn counts_response_body_bytes() {
+    let app = TestApp::new(&[]);
+    app.get("/").dispatch();
+    app.get("/").dispatch();
+    let served = sample(&app.scrape(), r#"http_response_size_bytes_total{method="GET",path="/"}"#);
+    assert_eq!(served, Some(2.0 * "Hello, world!".len() as f64));
+}
+
+#[test]
+fn counts_request_body_bytes() {
+    let app = TestApp::new(&[]);
+    let item = r#"{"name": "sized"}"#;
+    post_json(app.post("/items"), item).dispatch();
+    app.post("/items").header(ContentType::JSON).body(item).dispatch();
+    let received = sample(&app.scrape(), r#"http_request_size_bytes_total{method="POST",path="/items"}"#);
+    assert_eq!(received, Some(item.len() as f64));
+}
+
+#[test]
+fn fairing_instruments_any_rocket() {
+    let client = instrumented(&MetricsConfig::default(), routes![hello]);
+    assert_eq!(client.get("/hello").dispatch().into_string().unwrap(), "hello");
+    let body = encode(&client);
+    assert_eq!(sample(&body, r#"http_requests_total{method="GET",path="/hello",status="200"}"#), Some(1.0));
+    assert_eq!(http(&client).requests_in_progress.get(), 0.0);
+}
+
+#[test]
+fn duration_buckets_default_and_configured() {
+    let client = instrumented(&MetricsConfig::default(), routes![hello]);
+    client.get("/hello").dispatch();
+    assert!(encode(&client).contains(r#"http_request_duration_seconds_bucket{method="GET",path="/hello",status="200",le="0.005"}"#));
+
+    let config = MetricsConfig { duration_buckets: vec![0.2, 0.7], ..MetricsConfig::default() };
+    let client = instrumented(&config, routes![hello]);
+    client.get("/hello").dispatch();
+    let body = encode(&client);
+    assert!(body.contains(r#"http_request_duration_seconds_bucket{method="GET",path="/hello",status="200",le="0.7"}"#));
+    assert!(!body.contains(r#"status="200",le="0.005""#));
+}
+
+#[test]
+fn namespace_prefixes_every_metric() {
+    let app = TestApp::new(&[("METRICS_NAMESPACE", "myapp")]);
+    app.get("/").dispatch();
+    let body = app.scrape();
+    assert_eq!(sample(&body, r#"myapp_http_requests_total{method="GET",path="/",status="200"}"#), Some(1.0));
+    assert!(body.lines().filter(|line| !line.starts_with('#')).all(|line| line.starts_with("myapp_")), "{}", body);
+}
+
+#[test]
+fn legacy_request_total_only_on_request() {
+    let series = r#"{method="GET",path="/hello",status="200"}"#;
+    let client = instrumented(&MetricsConfig::default(), routes![hello]);
+    client.get("/hello").dispatch();
+    let body = encode(&client);
+    assert_eq!(sample(&body, &format!("http_requests_total{}", series)), Some(1.0));
+    assert!(!body.contains("http_request_total"));
+
+    let config = MetricsConfig { legacy_names: true, ..MetricsConfig::default() };
+    let client = instrumented(&config, routes![hello]);
+    client.get("/hello").dispatch();
+    let body = encode(&client);
+    assert_eq!(sample(&body, &format!("http_requests_total{}", series)), Some(1.0));
+    assert_eq!(sample(&body, &format!("http_request_total{}", series)), Some(1.0));
+}
+
+#[test]
+fn metrics_require_the_configured_token() {
+    let app = TestApp::new(&[("METRICS_BEARER_TOKEN", "secret")]);
+    assert_eq!(app.get("/metrics").dispatch().status(), Status::Unauthorized);
+    let wrong = app.get("/metrics").header(Header::new("Authorization", "Bearer secres")).dispatch();
+    assert_eq!(wrong.status(), Status::Unauthorized);
+}
+
+#[test]
+fn metrics_accept_the_configured_token() {
+    let app = TestApp::new(&[("METRICS_BEARER_TOKEN", "secret")]);
+    let response = app.get("/metrics").header(Header::new("Authorization", "Bearer secret")).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert!(response.into_string().unwrap().contains("items_count"));
+}
+
+#[test]
+fn metrics_are_open_without_a_token() {
+    let app = TestApp::new(&[]);
+    assert_eq!(app.get("/metrics").dispatch().status(), Status::Ok);
+    let ignored = app.get("/metrics").header(Header::new("Authorization", "Bearer anything")).dispatch();
+    assert_eq!(ignored.status(), Status::Ok);
+}
+
+#[test]
+fn gzip_scrape_decompresses_to_the_plain_one() {
+    let registry = Registry::new();
+    let answer = Gauge::new("answer", "The answer").unwrap();
+    answer.set(42.0);
+    registry.register(Box::new(answer)).unwrap();
+    let client = Client::tracked(rocket::custom(quiet()).manage(registry).mount("/", routes![families])).unwrap();
+
+    let plain = client.get("/families").dispatch().into_string().unwrap();
+    assert!(plain.contains("answer 42"), "{}", plain);
+    let response = client.get("/families").header(Header::new("Accept-Encoding", "deflate, gzip")).dispatch();
+    assert_eq!(response.headers().get_one("Content-Encoding"), Some("gzip"));
+    let mut decompressed = String::new();
+    GzDecoder::new(&response.into_bytes().unwrap()[..]).read_to_string(&mut decompressed).unwrap();
+    assert_eq!(decompressed, plain);
+
+    let refused = client.get("/families").header(Header::new("Accept-Encoding", "gzip;q=0")).dispatch();
+    assert_eq!(refused.headers().get_one("Content-Encoding"), None);
+    assert_eq!(refused.into_string().unwrap(), plain);
+}
+
+#[test]
+fn negotiates_openmetrics() {
+    let app = TestApp::new(&[]);
+    let response = app.get("/metrics").header(Header::new("Accept", "application/openmetrics-text; version=1.0.0")).dispatch();
+    assert!(response.content_type().unwrap().to_string().starts_with("application/openmetrics-text"));
+    assert!(response.into_string().unwrap().ends_with("# EOF\n"));
+
+    let response = app.get("/metrics").dispatch();
+    assert!(response.content_type().unwrap().to_string().starts_with("text/plain"));
+    assert!(!response.into_string().unwrap().contains("# EOF"));
+}
+
+#[test]
+fn probes_are_not_counted() {
+    let app = TestApp::new(&[]);
+    for _ in 0..20 {
+        assert_eq!(app.get("/healthz").dispatch().into_string().unwrap(), "ok");
+        assert_eq!(app.get("/readyz").dispatch().status(), Status::Ok);
+    }
+    let body = app.scrape();
+    assert!(!body.contains(r#"path="/healthz""#));
+    assert!(!body.contains(r#"path="/readyz""#));
+}
+
+#[test]
+fn readyz_fails_once_the_items_lock_is_poisoned() {
+    let app = TestApp::new(&[]);
+    assert_eq!(app.get("/readyz").dispatch().status(), Status::Ok);
+    assert_eq!(app.metrics().service_ready.get(), 1.0);
+
+    poison(app.rocket().state::<Items>().unwrap());
+    assert_eq!(app.get("/readyz").dispatch().status(), Status::ServiceUnavailable);
+    assert_eq!(app.metrics().service_ready.get(), 0.0);
+}
+
+#[test]
+fn lists_items_in_id_order() {
+    let app = TestApp::new(&[]);
+    for name in ["first", "second", "third"] {
+        app.create(name);
+    }
+    let page: Value = app.get("/items").dispatch().into_json().unwrap();
+    let names: Vec<&str> = page["items"].as_array().unwrap().iter().map(|item| item["name"].as_str().unwrap()).collect();
+    assert_eq!(names, ["first", "second", "third"]);
+    assert_eq!(page["items"][0]["item_id"], 1);
+    assert_eq!(page["total"], 3);
+    assert_eq!(page["limit"], 50);
+}
+
+#[test]
+fn pages_through_items() {
+    let app = TestApp::new(&[]);
+    for name in ["a", "b", "c"] {
+        app.create(name);
+    }
+    let page: Value = app.get("/items?limit=1&offset=1").dispatch().into_json().unwrap();
+    assert_eq!(page["items"].as_array().unwrap().len(), 1);
+    assert_eq!(page["items"][0]["name"], "b");
+    assert_eq!(page["total"], 3);
+
+    let past_end: Value = app.get("/items?offset=10").dispatch().into_json().unwrap();
+    assert_eq!(past_end["items"].as_array().unwrap().len(), 0);
+    assert_eq!(past_end["total"], 3);
+
+    let clamped: Value = app.get("/items?limit=0").dispatch().into_json().unwrap();
+    assert_eq!(clamped["limit"], 1);
+    assert_eq!(sample(&app.scrape(), "items_list_page_size_count"), Some(3.0));
+}
+
+#[test]
+fn rejects_empty_and_overlong_names() {
+    let app = TestApp::new(&[]);
+    for name in [String::new(), "x".repeat(MAX_NAME_CHARS + 1)] {
+        let response = post_json(app.post("/items"), &json!({ "name": name }).to_string()).dispatch();
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        assert!(response.into_json::<Value>().unwrap()["error"]["message"].is_string());
+    }
+    app.create(&"x".repeat(MAX_NAME_CHARS));
+    let id = app.create("valid")["item_id"].to_string();
+    let response = post_json(app.put(format!("/items/{}", id)), r#"{"name": ""}"#).dispatch();
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+
+    let body = app.scrape();
+    assert_eq!(sample(&body, r#"http_validation_errors_total{path="/items"}"#), Some(2.0));
+    assert_eq!(sample(&body, r#"http_requests_total{method="PUT",path="/items/<id>",status="422"}"#), Some(1.0));
+}
+
+/// Reads one HTTP/1.1 request from `stream` and answers it with 200.
+fn read_request(stream: TcpStream) -> (String, Vec<u8>) {
+    let mut reader = BufReader::new(stream);
+    let mut head = String::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        if line == "\r\n" || line.is_empty() {
+            break;
+        }
+        head.push_str(&line);
+    }
+    let length = head.lines()
+        .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|length| length.trim().parse().unwrap()))
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).unwrap();
+    reader.into_inner().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").unwrap();
+    (head, body)
+}
+
+#[test]
+fn pushes_to_the_pushgateway() {
+    let gateway = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", gateway.local_addr().unwrap());
+    let (sender, pushes) = mpsc::channel();
+    thread::spawn(move || {
+        let (stream, _) = gateway.accept().unwrap();
+        let _ = sender.send(read_request(stream));
+    });
+
+    let metrics = registry::build_registry(&MetricsConfig::default()).unwrap();
+    let runtime = rocket::tokio::runtime::Runtime::new().unwrap();
+    let _runtime = runtime.enter();
+    push::spawn_pushgateway(url, "tests".to_string(), Duration::from_secs(60), metrics.registry.clone());
+    let (head, body) = pushes.recv_timeout(Duration::from_secs(10)).expect("a push");
+    assert!(head.starts_with("POST /metrics/job/tests HTTP/1.1"), "{}", head);
+    assert!(body.windows(b"items_count".len()).any(|window| window == b"items_count"));
+}
+
+#[test]
+fn in_flight_gauges_count_the_request_being_served() {
+    let client = instrumented(&MetricsConfig::default(), routes![in_flight]);
+    assert_eq!(client.get("/in-flight").dispatch().into_string().unwrap(), "1 1");
+    assert_eq!(http(&client).requests_in_progress.get(), 0.0);
+    assert_eq!(http(&client).requests_in_progress_by_path.with_label_values(&["GET", "/in-flight"]).get(), 0.0);
+}
+
+#[test]
+fn in_flight_gauge_recovers_from_a_panicking_handler() {
+    let client = instrumented(&MetricsConfig::default(), routes![panics]);
+    assert_eq!(client.get("/panics").dispatch().status(), Status::InternalServerError);
+    assert_eq!(http(&client).requests_in_progress.get(), 0.0);
+    assert_eq!(http(&client).requests_in_progress_by_path.with_label_values(&["GET", "/panics"]).get(), 0.0);
+    assert_eq!(sample(&encode(&client), r#"http_requests_total{method="GET",path="/panics",status="500"}"#), Some(1.0));
+}
+
+#[test]
+fn counts_requests_by_status_class() {
+    let client = instrumented(&MetricsConfig::default(), routes![hello, panics]);
+    client.get("/hello").dispatch();
+    client.get("/hello").dispatch();
+    client.get("/nope").dispatch();
+    client.get("/panics").dispatch();
+    let body = encode(&client);
+    assert_eq!(sample(&body, r#"http_requests_by_class_total{class="2xx"}"#), Some(2.0));
+    assert_eq!(sample(&body, r#"http_requests_by_class_total{class="4xx"}"#), Some(1.0));
+    assert_eq!(sample(&body, r#"http_requests_by_class_total{class="5xx"}"#), Some(1.0));
+}
+
+#[test]
+fn items_survive_a_restart() {
+    let path = temp_path("items.json");
+    let file = path.to_str().unwrap();
+    {
+        let app = TestApp::new(&[("ITEMS_FILE", file)]);
+        app.create("kept");
+        app.create("deleted");
+        app.delete("/items/2").dispatch();
+    }
+    assert!(!PathBuf::from(format!("{}.tmp", file)).exists(), "the temporary file was renamed into place");
+    let app = TestApp::new(&[("ITEMS_FILE", file)]);
+    let item: Value = app.get("/items/1").dispatch().into_json().unwrap();
+    assert_eq!(item["name"], "kept");
+    assert_eq!(app.get("/items/2").dispatch().status(), Status::NotFound);
+    assert_eq!(app.create("after restart")["item_id"], 3);
+    assert_eq!(sample(&app.scrape(), "items_count"), Some(2.0));
+    drop(app);
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn writes_the_final_counts_on_shutdown() {
+    let path = temp_path("shutdown.prom");
+    let app = TestApp::new(&[("METRICS_SHUTDOWN_DUMP", path.to_str().unwrap())]);
+    app.get("/").dispatch();
+    let TestApp { client, _env } = app;
+    client.terminate();
+    let dump = fs::read_to_string(&path).unwrap();
+    assert_eq!(sample(&dump, r#"http_requests_total{method="GET",path="/",status="200"}"#), Some(1.0));
+    assert!(sample(&dump, "memory_total_bytes").is_some_and(|total| total > 0.0));
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn observes_item_name_lengths() {
+    let app = TestApp::new(&[]);
+    app.create("abc");
+    app.create(&"x".repeat(20));
+    post_json(app.post("/items"), r#"{"name": ""}"#).dispatch();
+    let body = app.scrape();
+    assert_eq!(sample(&body, r#"item_name_length_chars_bucket{le="8"}"#), Some(1.0));
+    assert_eq!(sample(&body, r#"item_name_length_chars_bucket{le="32"}"#), Some(2.0));
+    assert_eq!(sample(&body, "item_name_length_chars_count"), Some(2.0));
+    assert_eq!(sample(&body, "item_name_length_chars_sum"), Some(23.0));
+}
+
+#[test]
+fn filters_the_scrape_by_prefix() {
+    let app = TestApp::new(&[]);
+    app.get("/").dispatch();
+    let body = app.get("/metrics?prefix=http_").dispatch().into_string().unwrap();
+    assert!(body.contains("http_requests_total"));
+    assert!(body.lines().filter(|line| !line.starts_with('#')).all(|line| line.starts_with("http_")));
+    assert!(app.scrape().contains("memory_used_bytes"));
+}
+
+#[test]
+fn serves_metrics_as_json() {
+    let app = TestApp::new(&[]);
+    app.get("/").dispatch();
+    let families: Value = app.get("/metrics.json").dispatch().into_json().unwrap();
+    let families = families.as_array().unwrap();
+    let in_progress = families.iter().find(|family| family["name"] == "http_requests_in_progress").unwrap();
+    assert_eq!(in_progress["type"], "gauge");
+    assert!(in_progress["samples"][0]["value"].is_number());
+
+    let duration = families.iter().find(|family| family["name"] == "http_request_duration_seconds").unwrap();
+    assert_eq!(duration["type"], "histogram");
+    let samples = duration["samples"].as_array().unwrap();
+    assert!(samples.iter().any(|sample| sample["name"] == "http_request_duration_seconds_bucket" && sample["labels"]["le"] == "+Inf"));
+    assert!(samples.iter().any(|sample| sample["name"] == "http_request_duration_seconds_count"));
+}
+
+#[test]
+fn counts_unmatched_requests() {
+    let app = TestApp::new(&[]);
+    let response = app.get("/nonexistent").dispatch();
+    assert_eq!(response.into_json::<Value>().unwrap()["error"]["message"], "Not Found");
+    app.get("/items/999999").dispatch();
+
+    let body = app.scrape();
+    assert_eq!(sample(&body, r#"http_unmatched_requests_total{method="GET",status="404"}"#), Some(1.0));
+    assert_eq!(sample(&body, r#"http_requests_total{method="GET",path="unmatched",status="404"}"#), Some(1.0));
+    assert_eq!(sample(&body, r#"http_requests_total{method="GET",path="/items/<id>",status="404"}"#), Some(1.0));
+}
+
+#[test]
+fn reset_is_refused_unless_allowed() {
+    let app = TestApp::new(&[]);
+    app.get("/").dispatch();
+    assert_eq!(app.post("/metrics/reset").dispatch().status(), Status::Forbidden);
+    assert_eq!(sample(&app.scrape(), r#"http_requests_total{method="GET",path="/",status="200"}"#), Some(1.0));
+}
+
+#[test]
+fn reset_zeroes_the_request_metrics() {
+    let app = TestApp::new(&[("ALLOW_METRICS_RESET", "1")]);
+    app.get("/").dispatch();
+    app.get("/nope").dispatch();
+    post_json(app.post("/items"), r#"{"name": ""}"#).dispatch();
+    assert_eq!(app.post("/metrics/reset").dispatch().status(), Status::Ok);
+
+    let body = encode(&app);
+    assert!(!body.contains(r#"http_requests_total{method="GET",path="/""#), "{}", body);
+    assert!(!body.contains(r#"http_request_duration_seconds_count{method="GET",path="/""#), "{}", body);
+    assert_eq!(sample(&body, "http_requests_in_progress"), Some(0.0));
+    assert!(!body.contains("http_unmatched_requests_total{"));
+    assert!(!body.contains("http_validation_errors_total{"));
+}
+
+#[test]
+fn times_the_scrape() {
+    let app = TestApp::new(&[]);
+    app.scrape();
+    let body = app.scrape();
+    assert_eq!(sample(&body, "metrics_scrape_duration_seconds_count"), Some(1.0));
+    let help = body.lines().find_map(|line| line.strip_prefix("# HELP metrics_scrape_duration_seconds ")).unwrap();
+    assert!(!help.contains("polling system info"), "{}", help);
+}
+
+#[test]
+fn scrapes_read_cached_system_info() {
+    let fake = Arc::new(FakeSystem::default());
+    let app = TestApp::with_system(&[], SystemInfo(fake.clone()));
+    eventually("the first system refresh", || fake.mem_reads.load(Ordering::SeqCst) > 0);
+    let reads = fake.mem_reads.load(Ordering::SeqCst);
+    app.scrape();
+    app.scrape();
+    assert_eq!(fake.mem_reads.load(Ordering::SeqCst), reads);
+}
+
+#[test]
+fn system_gauges_come_from_the_provider() {
+    let fake = Arc::new(FakeSystem::default());
+    let app = TestApp::with_system(&[], SystemInfo(fake.clone()));
+    update_system_metrics(&*fake, app.metrics());
+
+    let body = app.scrape();
+    let expected = [
+        ("process_cpu_usage", 0.25),
+        ("system_load_average_1m", 1.5),
+        ("memory_total_bytes", 8_000.0),
+        ("memory_free_bytes", 3_000.0),
+        ("memory_used_bytes", 5_000.0),
+        ("swap_total_bytes", 2_000.0),
+        ("swap_used_bytes", 1_500.0),
+        ("threads_live", 7.0),
+        ("disk_total_bytes", 10_000.0),
+        ("disk_free_bytes", 4_000.0),
+        ("disk_used_bytes", 6_000.0),
+    ];
+    for (name, value) in expected {
+        assert_eq!(sample(&body, name), Some(value), "{}", name);
+    }
+    if cfg!(target_os = "linux") {
+        assert_eq!(sample(&body, "process_open_fds"), Some(12.0));
+        assert_eq!(sample(&body, "process_max_fds"), Some(1024.0));
+    }
+}
+
+#[test]
+fn bulk_create_reports_each_item() {
+    let app = TestApp::new(&[]);
+    let response = post_json(app.post("/items/bulk"), r#"[{"name": "ok"}, {"name": ""}]"#).dispatch();
+    assert_eq!(response.status(), Status::MultiStatus);
+    let body: Value = response.into_json().unwrap();
+    assert_eq!(body["created"], 1);
+    assert_eq!(body["failed"], 1);
+    assert_eq!(body["results"][0]["status"], "created");
+    assert_eq!(body["results"][1]["index"], 1);
+    assert_eq!(body["results"][1]["status"], "rejected");
+
+    let all_valid = post_json(app.post("/items/bulk"), r#"[{"name": "x"}, {"name": "y"}]"#).dispatch();
+    assert_eq!(all_valid.status(), Status::Ok);
+    let page: Value = app.get("/items").dispatch().into_json().unwrap();
+    assert_eq!(page["total"], 3);
+}
+
+#[test]
+fn attaches_trace_ids_as_exemplars() {
+    let app = TestApp::new(&[]);
+    let trace_id = "0af7651916cd43dd8448eb211c80319c";
+    app.get("/").header(Header::new("traceparent", format!("00-{}-b7ad6b7169203331-01", trace_id))).dispatch();
+    let body = app.get("/metrics").header(Header::new("Accept", "application/openmetrics-text")).dispatch().into_string().unwrap();
+    let bucket = body.lines()
+        .find(|line| line.starts_with("http_request_duration_seconds_bucket{") && line.contains(trace_id))
+        .expect("a bucket with the exemplar");
+    assert!(bucket.contains(r#"path="/""#), "{}", bucket);
+    assert!(bucket.contains(&format!(r#"# {{trace_id="{}"}}"#, trace_id)), "{}", bucket);
+}
+
+#[test]
+fn request_spans_carry_the_request_fields() {
+    let client = instrumented(&MetricsConfig::default(), routes![hello]);
+    let fields = SpanFields::default();
+    let subscriber = tracing_subscriber::registry().with(fields.clone());
+    tracing::subscriber::with_default(subscriber, || {
+        client.get("/hello").dispatch();
+    });
+    let fields = fields.0.lock().unwrap();
+    assert_eq!(fields.get("method").map(String::as_str), Some("GET"));
+    assert_eq!(fields.get("path").map(String::as_str), Some("/hello"));
+    assert_eq!(fields.get("status").map(String::as_str), Some("200"));
+    assert!(fields.get("duration_ms").and_then(|duration| duration.parse::<f64>().ok()).is_some());
+}
+
+#[test]
+fn bind_address_and_port_from_the_environment() {
+    let _env = TestEnv::set(&[("BIND_ADDRESS", "127.0.0.2"), ("PORT", "9123")]);
+    let config: rocket::Config = config::bind_figment(rocket::Config::figment()).extract().unwrap();
+    assert_eq!(config.address, IpAddr::from([127, 0, 0, 2]));
+    assert_eq!(config.port, 9123);
+
+    env::remove_var("BIND_ADDRESS");
+    env::set_var("PORT", "http");
+    let config: rocket::Config = config::bind_figment(rocket::Config::figment()).extract().unwrap();
+    env::remove_var("PORT");
+    assert_eq!(config.port, rocket::Config::figment().extract::<rocket::Config>().unwrap().port);
+}
+
+#[test]
+fn concurrent_creates_get_distinct_ids() {
+    let _env = TestEnv::set(&[]);
+    let rocket = build(host());
+    let ids = rocket::execute(async move {
+        let client = Arc::new(asynchronous::Client::tracked(rocket).await.unwrap());
+        let creates: Vec<_> = (0..200).map(|n| {
+            let client = client.clone();
+            rocket::tokio::spawn(async move {
+                let body = json!({ "name": format!("item-{}", n) }).to_string();
+                let response = client.post("/items").header(ContentType::JSON)
+                    .header(Header::new("Content-Length", body.len().to_string()))
+                    .body(body)
+                    .dispatch().await;
+                response.into_json::<Value>().await.unwrap()["item_id"].as_u64().unwrap()
+            })
+        }).collect();
+        join_all(creates).await.into_iter().map(Result::unwrap).collect::<Vec<_>>()
+    });
+    assert_eq!(ids.iter().collect::<HashSet<_>>().len(), 200);
+}
+
+#[test]
+fn ids_are_not_reused_after_a_delete() {
+    let app = TestApp::new(&[]);
+    assert_eq!(app.create("a")["item_id"], 1);
+    assert_eq!(app.create("b")["item_id"], 2);
+    app.delete("/items/2").dispatch();
+    assert_eq!(app.create("c")["item_id"], 3);
+}
+
+#[test]
+fn reads_items_files_without_a_next_id() {
+    let path = temp_path("legacy-items.json");
+    fs::write(&path, r#"{"1": "old", "4": "older"}"#).unwrap();
+    let app = TestApp::new(&[("ITEMS_FILE", path.to_str().unwrap())]);
+    let item: Value = app.get("/items/4").dispatch().into_json().unwrap();
+    assert_eq!(item["name"], "older");
+    assert_eq!(app.create("new")["item_id"], 5);
+    drop(app);
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn reads_items_with_fields_it_does_not_know() {
+    let path = temp_path("newer-items.json");
+    fs::write(&path, r#"{"next_id": 3, "items": {"2": {"name": "kept", "priority": 1}}}"#).unwrap();
+    let app = TestApp::new(&[("ITEMS_FILE", path.to_str().unwrap())]);
+    let item: Value = app.get("/items/2").dispatch().into_json().unwrap();
+    assert_eq!(item["name"], "kept");
+    assert_eq!(app.create("new")["item_id"], 3);
+    drop(app);
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn moves_an_unreadable_items_file_aside() {
+    let path = temp_path("corrupt-items.json");
+    fs::write(&path, "{not json").unwrap();
+    let app = TestApp::new(&[("ITEMS_FILE", path.to_str().unwrap())]);
+    app.create("fresh");
+    drop(app);
+
+    let prefix = format!("{}.unreadable-", path.file_name().unwrap().to_str().unwrap());
+    let aside: Vec<PathBuf> = fs::read_dir(env::temp_dir()).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|aside| aside.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.starts_with(&prefix)))
+        .collect();
+    assert_eq!(aside.len(), 1, "{:?}", aside);
+    assert_eq!(fs::read_to_string(&aside[0]).unwrap(), "{not json");
+    assert!(fs::read_to_string(&path).unwrap().contains("fresh"));
+    fs::remove_file(&aside[0]).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn mirrors_requests_to_statsd() {
+    let agent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    agent.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let statsd = StatsdFairing::new(&agent.local_addr().unwrap().to_string(), "").unwrap();
+    let client = Client::tracked(rocket::custom(quiet()).mount("/", routes![hello]).attach(statsd)).unwrap();
+    client.get("/hello").dispatch();
+
+    let mut packet = [0; 1024];
+    let counter = loop {
+        let size = agent.recv(&mut packet).expect("a StatsD packet");
+        let line = String::from_utf8_lossy(&packet[..size]).to_string();
+        if line.starts_with("http_requests_total") {
+            break line;
+        }
+    };
+    assert!(counter.starts_with("http_requests_total:1|c|#"), "{}", counter);
+    for tag in ["method:GET", "path:/hello", "status:200"] {
+        assert!(counter.contains(tag), "{}", counter);
+    }
+}
+
+#[test]
+fn mirrors_requests_to_opentelemetry() {
+    let exporter = InMemoryMetricExporter::default();
+    let otel = OtelFairing::with_exporter(exporter.clone(), "tests".to_string(), &MetricsConfig::default());
+    let client = Client::tracked(rocket::custom(quiet()).mount("/", routes![hello]).attach(otel)).unwrap();
+    client.get("/hello").dispatch();
+    client.terminate();
+
+    let exported = exporter.get_finished_metrics().unwrap();
+    let names: HashSet<&str> = exported.iter()
+        .flat_map(|resource| resource.scope_metrics())
+        .flat_map(|scope| scope.metrics())
+        .map(|metric| metric.name())
+        .collect();
+    assert!(names.contains("http_requests_total"), "{:?}", names);
+    assert!(names.contains("http_request_duration_seconds"), "{:?}", names);
+}
+
+#[test]
+fn disk_gauges_describe_the_working_directory() {
+    let body = TestApp::new(&[]).refreshed_scrape();
+    let total = sample(&body, "disk_total_bytes").unwrap();
+    let free = sample(&body, "disk_free_bytes").unwrap();
+    assert!(total > 0.0);
+    assert!(free <= total, "{} free of {}", free, total);
+    assert_eq!(sample(&body, "disk_used_bytes"), Some(total - free));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn open_fds_count_opened_files() {
+    let app = TestApp::new(&[]);
+    let before = sample(&app.refreshed_scrape(), "process_open_fds").unwrap();
+    let files: Vec<fs::File> = (0..32).map(|_| fs::File::open("/proc/self/limits").unwrap()).collect();
+    let body = app.refreshed_scrape();
+    drop(files);
+    let during = sample(&body, "process_open_fds").unwrap();
+    assert!(during > before, "{} open, then {} with 32 more", before, during);
+    assert!(sample(&body, "process_max_fds").is_some_and(|max| max > 0.0));
+}
+
+#[test]
+fn readyz_fails_while_the_items_file_cannot_be_written() {
+    let dir = temp_path("unwritable");
+    let app = TestApp::new(&[("ITEMS_FILE", dir.join("items.json").to_str().unwrap())]);
+    app.create("unsaved");
+    assert_eq!(app.get("/readyz").dispatch().status(), Status::ServiceUnavailable);
+    assert_eq!(app.metrics().service_ready.get(), 0.0);
+
+    fs::create_dir(&dir).unwrap();
+    app.create("saved");
+    assert_eq!(app.get("/readyz").dispatch().status(), Status::Ok);
+    assert_eq!(app.metrics().service_ready.get(), 1.0);
+    drop(app);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn apps_keep_their_own_counts() {
+    let _env = TestEnv::set(&[]);
+    let first = Client::tracked(build(host())).unwrap();
+    let second = Client::tracked(build(host())).unwrap();
+    first.get("/").dispatch();
+    first.get("/").dispatch();
+    second.get("/").dispatch();
+    let series = r#"http_requests_total{method="GET",path="/",status="200"}"#;
+    assert_eq!(sample(&encode(&first), series), Some(2.0));
+    assert_eq!(sample(&encode(&second), series), Some(1.0));
+}
+
+#[test]
+fn counts_head_and_options_requests_under_their_own_method() {
+    let app = TestApp::new(&[]);
+    assert_eq!(app.head("/").dispatch().status(), Status::Ok);
+    app.options("/items").dispatch();
+    let body = app.scrape();
+    assert_eq!(sample(&body, r#"http_requests_total{method="HEAD",path="/",status="200"}"#), Some(1.0));
+    assert!(body.contains(r#"http_requests_total{method="OPTIONS""#));
+    assert!(!body.contains(r#"http_requests_total{method="GET",path="/""#));
+}
+
+#[test]
+fn patch_updates_only_the_given_fields() {
+    let app = TestApp::new(&[]);
+    app.create("old");
+    let unchanged: Value = post_json(app.patch("/items/1"), "{}").dispatch().into_json().unwrap();
+    assert_eq!(unchanged["name"], "old");
+    let response = post_json(app.patch("/items/1"), r#"{"name": "new"}"#).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let item: Value = app.get("/items/1").dispatch().into_json().unwrap();
+    assert_eq!(item["name"], "new");
+    assert_eq!(post_json(app.patch("/items/9"), r#"{"name": "new"}"#).dispatch().status(), Status::NotFound);
+}
+
+#[test]
+fn rate_limits_each_client() {
+    let app = TestApp::new(&[("RATE_LIMIT_PER_SEC", "2")]);
+    let client = "10.0.0.1:4000".parse().unwrap();
+    let statuses: Vec<Status> = (0..3).map(|_| app.get("/").remote(client).dispatch().status()).collect();
+    assert_eq!(statuses, [Status::Ok, Status::Ok, Status::TooManyRequests]);
+    assert_eq!(app.get("/").remote("10.0.0.2:4000".parse().unwrap()).dispatch().status(), Status::Ok);
+    assert_eq!(sample(&app.scrape(), r#"http_rate_limited_total{path="/"}"#), Some(1.0));
+}
+
+#[test]
+fn times_the_items_lock() {
+    let app = TestApp::new(&[]);
+    app.create("a");
+    app.get("/items").dispatch();
+    app.delete("/items/1").dispatch();
+    let body = app.scrape();
+    assert!(sample(&body, "items_lock_wait_seconds_count").is_some_and(|count| count >= 3.0));
+    let held = sample(&body, "items_lock_hold_seconds_count").unwrap();
+    assert!(held >= 3.0);
+    assert_eq!(sample(&body, r#"items_lock_hold_seconds_bucket{le="1"}"#), Some(held));
+}
+
+#[test]
+fn serves_a_single_family() {
+    let app = TestApp::new(&[]);
+    let response = app.get("/metrics/items_count").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let body = response.into_string().unwrap();
+    assert_eq!(body.lines().filter(|line| line.starts_with("# TYPE")).collect::<Vec<_>>(), ["# TYPE items_count gauge"]);
+    assert_eq!(app.get("/metrics/nope").dispatch().status(), Status::NotFound);
+}
+
+#[test]
+fn errors_are_json() {
+    let app = TestApp::new(&[]);
+    let missing_item: Value = app.get("/items/42").dispatch().into_json().unwrap();
+    assert_eq!(missing_item, json!({ "error": { "code": 404, "message": "Item with id 42 not found" } }));
+    let no_route: Value = app.get("/nope").dispatch().into_json().unwrap();
+    assert_eq!(no_route, json!({ "error": { "code": 404, "message": "Not Found" } }));
+}
+
+#[test]
+fn counts_slow_requests() {
+    let config = MetricsConfig { slow_request_threshold: Duration::from_millis(10), ..MetricsConfig::default() };
+    let client = instrumented(&config, routes![hello, slow]);
+    client.get("/slow").dispatch();
+    client.get("/hello").dispatch();
+    let body = encode(&client);
+    assert_eq!(sample(&body, r#"http_slow_requests_total{path="/slow"}"#), Some(1.0));
+    assert_eq!(sample(&body, r#"http_slow_requests_total{path="/hello"}"#), Some(0.0));
+}
+
+#[test]
+fn counts_responses_by_content_type() {
+    let app = TestApp::new(&[]);
+    app.create("a");
+    app.get("/").dispatch();
+    let body = app.scrape();
+    assert_eq!(sample(&body, r#"http_responses_by_content_type_total{content_type="application/json"}"#), Some(1.0));
+    assert_eq!(sample(&body, r#"http_responses_by_content_type_total{content_type="text/plain"}"#), Some(1.0));
+}
+
+#[test]
+fn snapshot_reset_needs_allow_metrics_reset() {
+    let series = r#"http_requests_total{method="GET",path="/",status="200"}"#;
+    let app = TestApp::new(&[]);
+    app.get("/").dispatch();
+    assert_eq!(app.get("/metrics/snapshot?reset=true").dispatch().status(), Status::Forbidden);
+    assert_eq!(sample(&app.scrape(), series), Some(1.0));
+    let snapshot: Value = app.get("/metrics/snapshot").dispatch().into_json().unwrap();
+    assert_eq!(snapshot["reset"], false);
+    drop(app);
+
+    let app = TestApp::new(&[("ALLOW_METRICS_RESET", "1")]);
+    app.get("/").dispatch();
+    let snapshot: Value = app.get("/metrics/snapshot?reset=true").dispatch().into_json().unwrap();
+    assert_eq!(snapshot["reset"], true);
+    assert!(snapshot["requests"].as_array().unwrap().iter().any(|total| total["path"] == "/"));
+    assert_eq!(sample(&app.scrape(), series), None);
+}
+
+#[test]
+fn reports_the_runtime_workers() {
+    let app = TestApp::new(&[]);
+    assert!(sample(&app.scrape(), "tokio_worker_threads").is_some_and(|workers| workers >= 1.0));
+}
+
+#[test]
+fn serves_duration_quantiles() {
+    let app = TestApp::new(&[]);
+    app.get("/").dispatch();
+    let body = app.scrape();
+    for quantile in ["0.5", "0.9", "0.99"] {
+        let series = format!(r#"http_request_duration_summary_seconds{{method="GET",path="/",quantile="{}"}}"#, quantile);
+        assert!(sample(&body, &series).is_some(), "{}", series);
+    }
+    assert_eq!(sample(&body, r#"http_request_duration_summary_seconds_count{method="GET",path="/"}"#), Some(1.0));
+}
+
+#[test]
+fn create_answers_201_with_a_location() {
+    let app = TestApp::new(&[]);
+    let response = post_json(app.post("/items"), r#"{"name": "located"}"#).dispatch();
+    assert_eq!(response.status(), Status::Created);
+    assert_eq!(response.headers().get_one("Location"), Some("/items/1"));
+}
+
+#[test]
+fn conditional_reads_answer_304() {
+    let app = TestApp::new(&[]);
+    app.create("tagged");
+    let response = app.get("/items/1").dispatch();
+    let etag = response.headers().get_one("ETag").unwrap().to_string();
+    let cached = app.get("/items/1").header(Header::new("If-None-Match", etag.clone())).dispatch();
+    assert_eq!(cached.status(), Status::NotModified);
+    assert_eq!(sample(&app.scrape(), "http_cache_hits_total"), Some(1.0));
+
+    post_json(app.put("/items/1"), r#"{"name": "changed"}"#).dispatch();
+    assert_eq!(app.get("/items/1").header(Header::new("If-None-Match", etag)).dispatch().status(), Status::Ok);
+}
+
+#[test]
+fn computes_requests_per_second() {
+    let app = TestApp::new(&[]);
+    eventually("a positive request rate", || {
+        app.get("/").dispatch();
+        http(&app).requests_per_second.get() > 0.0
+    });
+}
+
+#[test]
+fn items_round_trip_every_field() {
+    let app = TestApp::new(&[]);
+    post_json(app.post("/items"), r#"{"name": "full", "description": "all fields", "tags": ["a", "b"]}"#).dispatch();
+    let item: Value = app.get("/items/1").dispatch().into_json().unwrap();
+    assert_eq!(item, json!({ "item_id": 1, "name": "full", "description": "all fields", "tags": ["a", "b"] }));
+    assert_eq!(sample(&app.scrape(), "items_tagged_total"), Some(1.0));
+}
+
+#[test]
+fn searches_names_ignoring_case() {
+    let app = TestApp::new(&[]);
+    for name in ["Apple", "kiwi", "pineapple"] {
+        app.create(name);
+    }
+    let found: Value = app.get("/items/search?q=APPLE").dispatch().into_json().unwrap();
+    let ids: Vec<u64> = found["items"].as_array().unwrap().iter().map(|item| item["item_id"].as_u64().unwrap()).collect();
+    assert_eq!(ids, [1, 3]);
+    assert_eq!(app.get("/items/search?q=").dispatch().status(), Status::BadRequest);
+    let body = app.scrape();
+    assert_eq!(sample(&body, "items_search_total"), Some(1.0));
+    assert_eq!(sample(&body, "items_search_results_sum"), Some(2.0));
+}
+
+#[test]
+fn registering_twice_names_the_metric() {
+    let registry = Registry::new();
+    let gauge = IntGauge::new("twice_registered", "help").unwrap();
+    registry::register(&registry, &gauge).unwrap();
+    let error = registry::register(&registry, &gauge).unwrap_err().to_string();
+    assert!(error.contains("could not register twice_registered"), "{}", error);
+}
+
+#[test]
+fn echoes_the_duration_when_asked() {
+    let app = TestApp::new(&[("RESPONSE_TIME_HEADER", "1")]);
+    let duration: f64 = app.get("/").dispatch().headers().get_one("X-Response-Time-Ms").unwrap().parse().unwrap();
+    assert!(duration >= 0.0);
+    drop(app);
+
+    let app = TestApp::new(&[]);
+    assert!(app.get("/").dispatch().headers().get_one("X-Response-Time-Ms").is_none());
+}
+
+#[test]
+fn hashes_clients_into_a_bounded_number_of_buckets() {
+    let app = TestApp::new(&[("METRICS_CLIENT_BUCKETS", "4")]);
+    let first = "192.0.2.1:1000".parse().unwrap();
+    app.get("/").remote(first).dispatch();
+    app.get("/").remote(first).dispatch();
+    app.get("/").remote("192.0.2.2:1000".parse().unwrap()).dispatch();
+
+    let counts: Vec<(u64, f64)> = app.scrape().lines()
+        .filter_map(|line| line.strip_prefix(r#"http_requests_by_client_total{client_bucket=""#))
+        .map(|line| {
+            let (bucket, value) = line.split_once(r#""} "#).unwrap();
+            (bucket.parse().unwrap(), value.parse().unwrap())
+        })
+        .collect();
+    assert!(counts.iter().all(|(bucket, _)| *bucket < 4), "{:?}", counts);
+    assert_eq!(counts.iter().map(|(_, count)| count).sum::<f64>(), 3.0);
+    assert!(counts.iter().any(|(_, count)| *count >= 2.0), "{:?}", counts);
+}
+
+#[test]
+fn estimates_the_store_size() {
+    let app = TestApp::new(&[]);
+    app.create("a");
+    let small = sample(&app.scrape(), "items_store_bytes").unwrap();
+    let name = "x".repeat(100);
+    app.create(&name);
+    let large = sample(&app.scrape(), "items_store_bytes").unwrap();
+    assert!(small >= 1.0);
+    assert!(large >= small + name.len() as f64);
+}
+
+#[test]
+fn tls_needs_both_paths() {
+    let _env = TestEnv::set(&[("TLS_CERT_PATH", "cert.pem")]);
+    assert!(config::tls_figment(rocket::Config::figment()).is_err());
+    drop(_env);
+
+    let _env = TestEnv::set(&[("TLS_CERT_PATH", "cert.pem"), ("TLS_KEY_PATH", "key.pem")]);
+    let config: rocket::Config = config::tls_figment(rocket::Config::figment()).unwrap().extract().unwrap();
+    assert!(config.tls_enabled());
+    drop(_env);
+
+    let _env = TestEnv::set(&[]);
+    let config: rocket::Config = config::tls_figment(rocket::Config::figment()).unwrap().extract().unwrap();
+    assert!(!config.tls_enabled());
+}
+
+#[test]
+fn counts_malformed_json() {
+    let app = TestApp::new(&[]);
+    let response = post_json(app.post("/items"), "{").dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    let error: Value = response.into_json().unwrap();
+    assert!(error["error"]["message"].as_str().unwrap().starts_with("invalid JSON body"));
+    let body = app.scrape();
+    assert_eq!(sample(&body, r#"http_json_errors_total{path="/items"}"#), Some(1.0));
+    assert_eq!(sample(&body, r#"http_requests_total{method="POST",path="/items",status="400"}"#), Some(1.0));
+}
+
+#[test]
+fn sampling_rate_zero_skips_the_duration_series() {
+    let app = TestApp::new(&[("DURATION_SAMPLE_RATE", "0")]);
+    app.get("/").dispatch();
+    let body = app.scrape();
+    assert_eq!(sample(&body, r#"http_requests_total{method="GET",path="/",status="200"}"#), Some(1.0));
+    assert_eq!(sample(&body, r#"http_request_duration_seconds_count{method="GET",path="/",status="200"}"#), Some(0.0));
+}
+
+#[test]
+fn counts_items_as_plain_text() {
+    let app = TestApp::new(&[]);
+    app.create("a");
+    app.create("b");
+    let response = app.get("/items/count").dispatch();
+    assert_eq!(response.content_type(), Some(ContentType::Plain));
+    assert_eq!(response.into_string().unwrap(), "2");
+}
+
+#[test]
+fn caps_distinct_path_labels() {
+    let app = TestApp::new(&[("MAX_PATH_CARDINALITY", "2")]);
+    app.get("/").dispatch();
+    app.get("/items").dispatch();
+    app.get("/items/count").dispatch();
+    let body = app.scrape();
+    assert_eq!(sample(&body, r#"http_requests_total{method="GET",path="other",status="200"}"#), Some(1.0));
+    assert_eq!(sample(&body, "metrics_cardinality_overflow_total"), Some(1.0));
+    assert!(!body.contains(r#"path="/items/count""#));
+}
+
+#[test]
+fn self_check_requests_the_index() {
+    let app = Launched::new(&[("SELF_CHECK", "1")]);
+    let series = r#"http_requests_total{method="GET",path="/",status="200"}"#;
+    eventually("the self-check request", || sample(&http_get(app.port, "/metrics", &[]).2, series) == Some(1.0));
+}
+
+#[test]
+fn names_items_created_without_a_body() {
+    let app = TestApp::new(&[]);
+    let response = app.post("/items").dispatch();
+    assert_eq!(response.status(), Status::Created);
+    assert_eq!(response.into_json::<Value>().unwrap()["name"], "untitled-1");
+    assert_eq!(app.create("given")["name"], "given");
+    assert_eq!(sample(&app.scrape(), "items_autonamed_total"), Some(1.0));
+    drop(app);
+
+    let app = TestApp::new(&[("DEFAULT_ITEM_NAME", "note")]);
+    assert_eq!(app.post("/items").dispatch().into_json::<Value>().unwrap()["name"], "note-1");
+    drop(app);
+
+    // A prefix too long for any id falls back rather than failing each create.
+    let long = "n".repeat(250);
+    let app = TestApp::new(&[("DEFAULT_ITEM_NAME", &long)]);
+    let response = app.post("/items").dispatch();
+    assert_eq!(response.status(), Status::Created);
+    assert_eq!(response.into_json::<Value>().unwrap()["name"], "untitled-1");
+    assert_eq!(app.create("next")["item_id"], 2);
+}
+
+#[test]
+fn recovers_from_a_poisoned_items_lock() {
+    let app = TestApp::new(&[]);
+    app.create("survivor");
+    poison(app.rocket().state::<Items>().unwrap());
+    assert_eq!(app.get("/items").dispatch().status(), Status::InternalServerError);
+    assert_eq!(sample(&app.scrape(), "items_lock_poisoned_total"), Some(1.0));
+    let page: Value = app.get("/items").dispatch().into_json().unwrap();
+    assert_eq!(page["items"][0]["name"], "survivor");
+}
+
+#[test]
+fn observes_the_time_between_requests() {
+    let app = TestApp::new(&[]);
+    app.get("/").dispatch();
+    thread::sleep(Duration::from_millis(200));
+    app.get("/").dispatch();
+    let body = app.scrape();
+    assert_eq!(sample(&body, "http_request_interarrival_seconds_count"), Some(1.0));
+    let gap = sample(&body, "http_request_interarrival_seconds_sum").unwrap();
+    assert!((0.2..2.0).contains(&gap), "{}", gap);
+}
+
+#[test]
+fn adds_cors_headers_for_allowed_origins() {
+    let allowed = "https://grafana.example.com";
+    let app = TestApp::new(&[("CORS_ALLOW_ORIGINS", allowed)]);
+    let response = app.get("/metrics").header(Header::new("Origin", allowed)).dispatch();
+    assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), Some(allowed));
+    let other = app.get("/metrics").header(Header::new("Origin", "https://evil.example.com")).dispatch();
+    assert!(other.headers().get_one("Access-Control-Allow-Origin").is_none());
+
+    let preflight = app.options("/items").header(Header::new("Origin", allowed)).dispatch();
+    assert_eq!(preflight.status(), Status::NoContent);
+    assert!(preflight.headers().get_one("Access-Control-Allow-Methods").unwrap().contains("DELETE"));
+}
+
+#[test]
+fn computes_the_error_ratio_per_route() {
+    let app = TestApp::new(&[]);
+    // Requests from before the sampler's first tick are part of its baseline.
+    thread::sleep(Duration::from_millis(100));
+    app.create("a");
+    for _ in 0..3 {
+        app.get("/items/1").dispatch();
+    }
+    app.get("/items/2").dispatch();
+    let ratio = || http(&app).route_error_ratio.with_label_values(&["/items/<id>"]).get();
+    eventually("the /items/<id> error ratio", || (ratio() - 0.25).abs() < 1e-9);
+}
+
+#[test]
+fn times_out_handlers_that_await() {
+    let metrics = registry::build_registry(&MetricsConfig::default()).unwrap();
+    let routes = timeout::with_timeout(routes![sleeps, slow, hello], Duration::from_millis(10), &metrics.http.request_timeouts_total);
+    let rocket = rocket::custom(quiet()).manage(metrics.registry.clone()).mount("/", routes);
+    let client = Client::tracked(metrics.http.attach(rocket)).unwrap();
+
+    let start = Instant::now();
+    assert_eq!(client.get("/sleeps").dispatch().status(), Status::ServiceUnavailable);
+    assert!(start.elapsed() < Duration::from_millis(500));
+    assert_eq!(client.get("/hello").dispatch().status(), Status::Ok);
+    // A synchronous handler can't be stopped and answers as usual.
+    assert_eq!(client.get("/slow").dispatch().into_string().unwrap(), "slow");
+
+    let body = encode(&client);
+    assert_eq!(sample(&body, r#"http_request_timeouts_total{path="/sleeps"}"#), Some(1.0));
+    assert_eq!(sample(&body, r#"http_request_timeouts_total{path="/slow"}"#), None);
+    assert_eq!(sample(&body, r#"http_requests_total{method="GET",path="/sleeps",status="503"}"#), Some(1.0));
+    let observed = sample(&body, r#"http_request_duration_seconds_sum{method="GET",path="/sleeps",status="503"}"#).unwrap();
+    assert!((0.01..0.5).contains(&observed), "{}", observed);
+}
+
+#[test]
+fn times_out_item_routes_waiting_for_the_store() {
+    let app = TestApp::new(&[("REQUEST_TIMEOUT_MS", "100")]);
+    let held = app.rocket().state::<Items>().unwrap().map.write().unwrap();
+    let start = Instant::now();
+    assert_eq!(app.get("/items").dispatch().status(), Status::ServiceUnavailable);
+    assert_eq!(post_json(app.post("/items"), r#"{"name": "late"}"#).dispatch().status(), Status::ServiceUnavailable);
+    assert!(start.elapsed() < Duration::from_secs(2), "{:?}", start.elapsed());
+    drop(held);
+
+    // The abandoned create still goes through once the lock is free.
+    eventually("the abandoned create", || app.get("/items/1").dispatch().status() == Status::Ok);
+    assert_eq!(sample(&app.scrape(), r#"http_request_timeouts_total{path="/items"}"#), Some(2.0));
+}
+
+#[test]
+fn maintenance_toggle_is_refused_without_a_token() {
+    let app = TestApp::new(&[]);
+    assert_eq!(app.post("/admin/maintenance?enabled=true").dispatch().status(), Status::Forbidden);
+    assert_eq!(app.get("/items").dispatch().status(), Status::Ok);
+    assert_eq!(sample(&app.scrape(), "maintenance_mode"), Some(0.0));
+}
+
+#[test]
+fn maintenance_toggle_needs_the_token() {
+    let app = TestApp::new(&[("METRICS_BEARER_TOKEN", "secret")]);
+    let bearer = || Header::new("Authorization", "Bearer secret");
+    assert_eq!(app.post("/admin/maintenance?enabled=true").dispatch().status(), Status::Unauthorized);
+    assert_eq!(app.get("/items").dispatch().status(), Status::Ok);
+
+    let response = app.post("/admin/maintenance?enabled=true").header(bearer()).dispatch();
+    assert_eq!(response.into_json::<Value>().unwrap(), json!({ "maintenance": true }));
+    assert_eq!(app.get("/items").dispatch().status(), Status::ServiceUnavailable);
+    app.post("/admin/maintenance").header(bearer()).dispatch();
+    assert_eq!(app.get("/items").dispatch().status(), Status::Ok);
+}
+
+#[test]
+fn streams_large_scrapes_in_chunks() {
+    let registry = Registry::new();
+    for n in 0..2_000 {
+        let gauge = IntGauge::new(format!("synthetic_{}", n), "a".repeat(100)).unwrap();
+        gauge.set(n);
+        registry.register(Box::new(gauge)).unwrap();
+    }
+    let expected = TextEncoder::new().encode_to_string(&registry.gather()).unwrap();
+    assert!(expected.len() > 256 * 1024);
+    let client = Client::tracked(rocket::custom(quiet()).manage(registry).mount("/", routes![big])).unwrap();
+
+    assert_eq!(client.get("/big?gzip=false").dispatch().into_string().unwrap(), expected);
+    let gzipped = client.get("/big?gzip=true").dispatch().into_bytes().unwrap();
+    let mut decompressed = String::new();
+    GzDecoder::new(&gzipped[..]).read_to_string(&mut decompressed).unwrap();
+    assert_eq!(decompressed, expected);
+}
+
+#[test]
+fn control_characters_stay_out_of_the_exposition() {
+    let app = TestApp::new(&[]);
+    app.get("/items/%01%02%7F").dispatch();
+    app.get("/%C3%28").header(Header::new("X-Request-Id", "id\u{1}with-control")).dispatch();
+    for accept in ["text/plain", "application/openmetrics-text"] {
+        let body = app.get("/metrics").header(Header::new("Accept", accept)).dispatch().into_string().unwrap();
+        assert!(!body.chars().any(|c| c.is_control() && c != '\n'), "control character in the {} scrape", accept);
+        assert!(!body.contains("with-control"));
+    }
+}
+
+#[test]
+fn admin_port_serves_only_the_admin_endpoints() {
+    let admin_port = free_port();
+    let allowed = "https://grafana.example.com";
+    let app = Launched::new(&[("ADMIN_PORT", &admin_port.to_string()), ("CORS_ALLOW_ORIGINS", allowed)]);
+    let origin = [("Origin", allowed)];
+
+    let (code, head, body) = http_get(admin_port, "/metrics", &origin);
+    assert_eq!(code, 200);
+    assert!(body.contains("items_count"));
+    assert!(head.to_ascii_lowercase().contains(&format!("access-control-allow-origin: {}", allowed)), "{}", head);
+    assert_eq!(http_get(admin_port, "/healthz", &[]).0, 200);
+    assert_eq!(http_get(admin_port, "/items", &[]).0, 404);
+
+    let (code, head, _) = http_get(app.port, "/items", &origin);
+    assert_eq!(code, 200);
+    assert!(head.to_ascii_lowercase().contains("access-control-allow-origin"), "{}", head);
+    assert_eq!(http_get(app.port, "/metrics", &[]).0, 404);
+}
+
+#[test]
+fn rejects_unknown_item_fields() {
+    let app = TestApp::new(&[]);
+    let response = post_json(app.post("/items"), r#"{"name": "a", "colour": "red"}"#).dispatch();
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    let error: Value = response.into_json().unwrap();
+    assert!(error["error"]["message"].as_str().unwrap().contains("colour"), "{}", error);
+    assert_eq!(app.get("/items/1").dispatch().status(), Status::NotFound);
+}
+
+#[test]
+fn access_log_mode_from_the_environment() {
+    for (value, mode) in [("json", AccessLog::Json), ("off", AccessLog::Off), ("tracing", AccessLog::Tracing), ("loud", AccessLog::Tracing)] {
+        let _env = TestEnv::set(&[("ACCESS_LOG", value)]);
+        assert_eq!(MetricsConfig::from_env().access_log, mode, "{}", value);
+    }
+}
+
+#[test]
+fn segments_requests_by_authentication() {
+    let app = TestApp::new(&[("METRICS_BEARER_TOKEN", "secret")]);
+    app.get("/").header(Header::new("Authorization", "Bearer secret")).dispatch();
+    app.get("/").dispatch();
+    app.get("/").header(Header::new("Authorization", "Bearer wrong")).dispatch();
+    let by_auth = &http(&app).requests_by_auth_total;
+    assert_eq!(by_auth.with_label_values(&["authenticated"]).get(), 1.0);
+    assert_eq!(by_auth.with_label_values(&["anonymous"]).get(), 2.0);
+}
+
+#[test]
+fn idempotency_keys_replay_created_items_only() {
+    let app = TestApp::new(&[]);
+    let keyed = |body: &str| post_json(app.post("/items"), body).header(Header::new("Idempotency-Key", "retry-1")).dispatch();
+    assert_eq!(keyed(r#"{"name": ""}"#).status(), Status::UnprocessableEntity);
+    let created = keyed(r#"{"name": "once"}"#);
+    assert_eq!(created.status(), Status::Created);
+    let replayed = keyed(r#"{"name": "once"}"#);
+    assert_eq!(replayed.status(), Status::Ok);
+    assert_eq!(replayed.into_json::<Value>().unwrap()["item_id"], 1);
+    let body = app.scrape();
+    assert_eq!(sample(&body, "items_count"), Some(1.0));
+    assert_eq!(sample(&body, "items_idempotent_replays_total"), Some(1.0));
+}
+
+#[test]
+fn reports_the_oldest_item_age() {
+    let app = TestApp::new(&[]);
+    assert_eq!(sample(&app.scrape(), "items_oldest_age_seconds"), Some(0.0));
+    app.create("old");
+    thread::sleep(Duration::from_millis(200));
+    app.create("new");
+    let age = sample(&app.scrape(), "items_oldest_age_seconds").unwrap();
+    assert!(age >= 0.2, "{}", age);
+}
+
+#[test]
+fn route_buckets_override_the_defaults_for_one_route() {
+    let app = TestApp::new(&[("METRICS_BUCKETS_ITEMS_ID", "0.123,0.456")]);
+    app.create("a");
+    app.get("/items/1").dispatch();
+    app.get("/").dispatch();
+    let body = app.scrape();
+    assert!(body.contains(r#"http_request_duration_seconds_bucket{method="GET",path="/items/<id>",status="200",le="0.123"}"#));
+    assert!(!body.contains(r#"path="/items/<id>",status="200",le="0.005""#));
+    assert!(body.contains(r#"http_request_duration_seconds_bucket{method="GET",path="/",status="200",le="0.005"}"#));
+    assert!(!body.contains(r#"path="/",status="200",le="0.123""#));
+}
+
+#[test]
+fn debug_stats_only_when_enabled() {
+    let app = TestApp::new(&[]);
+    assert_eq!(app.get("/debug/stats").dispatch().status(), Status::NotFound);
+    drop(app);
+
+    let app = TestApp::new(&[("DEBUG_ENDPOINTS", "1")]);
+    app.create("a");
+    let stats: Value = app.get("/debug/stats").dispatch().into_json().unwrap();
+    assert_eq!(stats["items"], 1);
+    assert!(stats["items_store_bytes"].as_u64().unwrap() > 0);
+    assert!(stats["threads"].as_u64().is_some_and(|threads| threads > 0));
+    assert_eq!(stats["requests_in_progress"], 1);
+}
+
+#[test]
+fn stable_order_sorts_families() {
+    let app = TestApp::new(&[("STABLE_METRICS_ORDER", "1")]);
+    app.get("/").dispatch();
+    app.get("/items").dispatch();
+    let body = app.scrape();
+    let families: Vec<&str> = body.lines()
+        .filter_map(|line| line.strip_prefix("# TYPE "))
+        .map(|line| line.split(' ').next().unwrap())
+        .collect();
+    assert!(families.is_sorted(), "{:?}", families);
+    let requests: Vec<&str> = body.lines().filter(|line| line.starts_with("http_requests_total{")).collect();
+    assert_eq!(requests.len(), 2);
+    assert!(requests.is_sorted(), "{:?}", requests);
+}
+
+#[test]
+fn counts_requests_by_http_version() {
+    let app = TestApp::new(&[]);
+    app.get("/").dispatch();
+    assert_eq!(sample(&app.scrape(), r#"http_requests_by_version_total{http_version="1.1"}"#), Some(1.0));
+}
+
+#[test]
+fn rejects_bodies_over_the_limit() {
+    let app = TestApp::new(&[("MAX_BODY_BYTES", "100")]);
+    let body = json!({ "name": "x".repeat(200) }).to_string();
+    let response = post_json(app.post("/items"), &body).dispatch();
+    assert_eq!(response.status(), Status::PayloadTooLarge);
+    assert_eq!(sample(&app.scrape(), r#"http_body_too_large_total{path="/items"}"#), Some(1.0));
+    app.create("small");
+}
+
+#[test]
+fn catalogs_registered_families() {
+    let app = TestApp::new(&[]);
+    app.get("/").dispatch();
+    let catalog: Value = app.get("/metrics/catalog").dispatch().into_json().unwrap();
+    let requests = catalog.as_array().unwrap().iter().find(|entry| entry["name"] == "http_requests_total").unwrap();
+    assert_eq!(requests["type"], "counter");
+    assert_eq!(requests["help"], "Total HTTP Requests");
+    assert_eq!(requests["labels"], json!(["method", "path", "status"]));
+}
+
+#[test]
+fn evicts_expired_items() {
+    let app = TestApp::new(&[("ITEM_TTL_SECONDS", "0.2"), ("ITEM_SWEEP_INTERVAL_SECONDS", "0.05")]);
+    app.create("short-lived");
+    eventually("the item to expire", || app.get("/items/1").dispatch().status() == Status::NotFound);
+    let body = app.scrape();
+    assert_eq!(sample(&body, "items_evicted_total"), Some(1.0));
+    assert_eq!(sample(&body, "items_count"), Some(0.0));
+}
+
+#[test]
+fn cached_series_keep_counts_apart() {
+    let client = instrumented(&MetricsConfig::default(), routes![hello, slow]);
+    for _ in 0..3 {
+        client.get("/hello").dispatch();
+        client.head("/hello").dispatch();
+        client.get("/slow").dispatch();
+    }
+    client.get("/nope").dispatch();
+    let body = encode(&client);
+    assert_eq!(sample(&body, r#"http_requests_total{method="GET",path="/hello",status="200"}"#), Some(3.0));
+    assert_eq!(sample(&body, r#"http_requests_total{method="HEAD",path="/hello",status="200"}"#), Some(3.0));
+    assert_eq!(sample(&body, r#"http_requests_total{method="GET",path="/slow",status="200"}"#), Some(3.0));
+    assert_eq!(sample(&body, r#"http_requests_total{method="GET",path="unmatched",status="404"}"#), Some(1.0));
+    assert_eq!(sample(&body, r#"http_requests_by_class_total{class="2xx"}"#), Some(9.0));
+    assert_eq!(sample(&body, r#"http_request_duration_seconds_count{method="GET",path="/slow",status="200"}"#), Some(3.0));
+
+    // A reset drops the cached children; requests after it land in the new ones.
+    client.rocket().state::<PrometheusFairing>().unwrap().reset();
+    client.get("/hello").dispatch();
+    let body = encode(&client);
+    assert_eq!(sample(&body, r#"http_requests_total{method="GET",path="/hello",status="200"}"#), Some(1.0));
+    assert_eq!(sample(&body, r#"http_requests_total{method="HEAD",path="/hello",status="200"}"#), None);
+}
+
+#[test]
+fn json_access_log_writes_one_line_per_request() {
+    let config = MetricsConfig { access_log: AccessLog::Json, ..MetricsConfig::default() };
+    let mut metrics = registry::build_registry(&config).unwrap();
+    let captured = Captured::default();
+    metrics.http.access_log_writer = AccessLogWriter::new(captured.clone());
+    let rocket = rocket::custom(quiet()).mount("/", routes![hello]);
+    let client = Client::tracked(metrics.http.attach(rocket)).unwrap();
+    client.get("/hello").remote("192.0.2.7:1000".parse().unwrap()).dispatch();
+    client.get("/nope").dispatch();
+
+    let lines = captured.lines();
+    assert_eq!(lines.len(), 2, "{:?}", lines);
+    let first: Value = serde_json::from_str(&lines[0]).unwrap();
+    assert_eq!(first["method"], "GET");
+    assert_eq!(first["path"], "/hello");
+    assert_eq!(first["status"], 200);
+    assert_eq!(first["client_ip"], "192.0.2.7");
+    assert!(first["duration_ms"].is_number());
+    assert!(first["timestamp"].as_str().is_some_and(|timestamp| timestamp.ends_with('Z')));
+    let second: Value = serde_json::from_str(&lines[1]).unwrap();
+    assert_eq!((&second["path"], &second["status"]), (&json!("unmatched"), &json!(404)));
+}
+
+#[test]
+fn json_access_log_goes_to_its_own_file() {
+    let path = temp_path("access.log");
+    let app = TestApp::new(&[("ACCESS_LOG", "json"), ("ACCESS_LOG_FILE", path.to_str().unwrap())]);
+    app.get("/").dispatch();
+    app.create("logged");
+    app.scrape();
+    drop(app);
+    let log = fs::read_to_string(&path).unwrap();
+    let paths: Vec<Value> = log.lines().map(|line| serde_json::from_str::<Value>(line).unwrap()["path"].clone()).collect();
+    assert_eq!(paths, [json!("/"), json!("/items")]);
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn reset_covers_rate_limits_cardinality_and_interarrival() {
+    let app = TestApp::new(&[("ALLOW_METRICS_RESET", "1"), ("RATE_LIMIT_PER_SEC", "1"), ("MAX_PATH_CARDINALITY", "1")]);
+    let client = "10.0.0.1:4000".parse().unwrap();
+    app.get("/").remote(client).dispatch();
+    assert_eq!(app.get("/").remote(client).dispatch().status(), Status::TooManyRequests);
+    app.get("/items").dispatch();
+    let body = app.scrape();
+    assert_eq!(sample(&body, r#"http_rate_limited_total{path="/"}"#), Some(1.0));
+    assert_eq!(sample(&body, "metrics_cardinality_overflow_total"), Some(1.0));
+    assert_eq!(sample(&body, "http_request_interarrival_seconds_count"), Some(2.0));
+
+    app.post("/metrics/reset").dispatch();
+    let body = app.scrape();
+    assert_eq!(sample(&body, r#"http_rate_limited_total{path="/"}"#), None);
+    assert_eq!(sample(&body, "http_request_interarrival_seconds_count"), None);
+    // The reset request itself took the one path label the reset freed.
+    assert_eq!(sample(&body, "metrics_cardinality_overflow_total"), Some(0.0));
+    assert_eq!(sample(&body, r#"http_requests_total{method="POST",path="/metrics/reset",status="200"}"#), Some(1.0));
+    app.get("/items").dispatch();
+    assert_eq!(sample(&app.scrape(), r#"http_requests_total{method="GET",path="other",status="200"}"#), Some(1.0));
+}
+
+#[test]
+fn unknown_fields_are_named_and_counted_once() {
+    let app = TestApp::new(&[]);
+    let response = post_json(app.post("/items"), r#"{"name": "a", "colour": "red"}"#).dispatch();
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    let error: Value = response.into_json().unwrap();
+    assert_eq!(error["error"]["field"], "colour");
+    let response = post_json(app.post("/items/bulk"), r#"[{"name": "a"}, {"name": "b", "size": 2}]"#).dispatch();
+    assert_eq!(response.into_json::<Value>().unwrap()["error"]["field"], "[1].size");
+    app.create("clean");
+
+    let body = app.scrape();
+    assert_eq!(sample(&body, r#"http_validation_errors_total{path="/items"}"#), Some(1.0));
+    assert_eq!(sample(&body, r#"http_validation_errors_total{path="/items/bulk"}"#), Some(1.0));
+    assert!(!body.contains("http_json_errors_total{"));
+    assert_eq!(sample(&body, "items_count"), Some(1.0));
+}
+
+#[test]
+fn type_errors_count_as_json_errors() {
+    let app = TestApp::new(&[]);
+    let response = post_json(app.post("/items"), r#"{"name": 5}"#).dispatch();
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    assert!(response.into_json::<Value>().unwrap()["error"].get("field").is_none());
+    let body = app.scrape();
+    assert_eq!(sample(&body, r#"http_json_errors_total{path="/items"}"#), Some(1.0));
+    assert!(!body.contains("http_validation_errors_total{"));
+}
+
+#[test]
+fn excluded_paths_add_to_the_probes() {
+    let app = TestApp::new(&[("METRICS_EXCLUDE_PATHS", "/items/<id>, /healthz")]);
+    app.create("a");
+    app.get("/items/1").dispatch();
+    app.get("/healthz").dispatch();
+    app.get("/readyz").dispatch();
+    let body = app.scrape();
+    assert!(!body.contains(r#"path="/items/<id>""#));
+    assert!(!body.contains(r#"path="/healthz""#));
+    assert!(!body.contains(r#"path="/readyz""#));
+    assert!(!body.contains(r#"path="/metrics""#));
+    assert_eq!(sample(&body, r#"http_requests_total{method="POST",path="/items",status="201"}"#), Some(1.0));
+    assert_eq!(http(&app).excluded_paths, ["/metrics", "/healthz", "/readyz", "/items/<id>"]);
+}
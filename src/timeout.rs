@@ -0,0 +1,63 @@
+use std::time::{Duration, Instant};
+
+use prometheus::CounterVec;
+use rocket::http::Status;
+use rocket::route::{Handler, Outcome};
+use rocket::tokio::time;
+use rocket::{Data, Request, Route};
+
+/// Gives up on a route's handler after `timeout` and answers `503` instead,
+/// counting it in `http_request_timeouts_total`. Only handlers that await can
+/// be given up on: the handler's future is dropped at its next await point.
+/// The item routes await their store work, which runs on the blocking pool and
+/// finishes in the background when given up on. A synchronous handler blocks
+/// the thread polling it, so it runs to completion and its response goes out
+/// as is; such overruns are logged but not counted.
+#[derive(Clone)]
+struct TimeoutHandler {
+    handler: Box<dyn Handler>,
+    timeout: Duration,
+    path: String,
+    timeouts: CounterVec,
+}
+
+#[rocket::async_trait]
+impl Handler for TimeoutHandler {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        let start = Instant::now();
+        match time::timeout(self.timeout, self.handler.handle(request, data)).await {
+            Ok(outcome) => {
+                if start.elapsed() > self.timeout {
+                    warn!("{} {} ran {:?} past its {:?} timeout without yielding; the timeout only bounds handlers that await",
+                        request.method(), request.uri(), start.elapsed() - self.timeout, self.timeout);
+                }
+                outcome
+            }
+            Err(_) => {
+                warn!("{} {} timed out after {:?}", request.method(), request.uri(), self.timeout);
+                self.timeouts.with_label_values(&[&self.path]).inc();
+                Outcome::Error(Status::ServiceUnavailable)
+            }
+        }
+    }
+}
+
+/// Wraps the handlers of `routes` in a `timeout`, except for `/metrics` and
+/// the routes below it, so scrapes of a struggling server still get through.
+pub fn with_timeout(routes: Vec<Route>, timeout: Duration, timeouts: &CounterVec) -> Vec<Route> {
+    routes.into_iter()
+        .map(|mut route| {
+            let path = route.uri.path().to_string();
+            if path == "/metrics" || path.starts_with("/metrics/") {
+                return route;
+            }
+            route.handler = Box::new(TimeoutHandler {
+                handler: route.handler,
+                timeout,
+                path,
+                timeouts: timeouts.clone(),
+            });
+            route
+        })
+        .collect()
+}